@@ -0,0 +1,554 @@
+//! Controller API client.
+//!
+//! `authorize` registers the interface key with the controller and returns
+//! where to fetch the control stream, `connect` opens that stream and
+//! `offline` tells the controller we are leaving.
+//!
+//! A controller may answer `authorize` with 401, a base64 nonce in
+//! `x-challenge` and its X25519 key in `x-controller-key`. The request is
+//! then repeated with the nonce in `CHALLENGE` and a MAC in `PROOF` that only
+//! the holder of the interface's private key can compute.
+//!
+//! A provision code sent with `authorize` is answered with `x-provision:
+//! bound`, `pending` or `rejected`, the latter with the reason in
+//! `x-provision-error` (`expired`, `invalid` or free text).
+//!
+//! `authorize` offers the newest stream format with `PROTOCOL-VERSION`, the
+//! controller picks one up to that in `x-protocol`. Controllers that do not
+//! answer it speak version 1. Likewise `TRANSPORT` lists the ways the
+//! client can read the stream and `x-transport` picks one, see
+//! [`crate::transport`]. `HEARTBEAT` asks for a heartbeat every so many
+//! seconds, a controller that agrees answers the interval it will keep in
+//! `x-heartbeat`. Without that answer a quiet stream is no sign of trouble.
+//!
+//! Every request goes through the same [`Tls`] settings, see
+//! [`crate::tls`]. With pins set requests go over connections of our own,
+//! see [`transport::request`], as the certificate must be checked before
+//! anything is sent.
+
+use crate::tls::Tls;
+use crate::transport::{self, Stream, Transport};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use reqwest::header::HeaderMap;
+use reqwest::{Method, StatusCode};
+use sha2::Sha256;
+use std::fmt;
+use std::io::{self, Read};
+use std::time::Duration;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Newest control-stream format this client speaks, see [`crate::message`].
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The controller's answer to a provision code, from `x-provision`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Provision {
+    /// The key is enrolled, the code is spent.
+    Bound,
+    /// The code was accepted and waits for approval in the dashboard.
+    Pending,
+}
+
+/// Why the controller refused a provision code, from `x-provision-error`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    Expired,
+    Invalid,
+    Other(String),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Transport failure.
+    Http(reqwest::Error),
+    /// Transport failure outside of reqwest, on a WebSocket.
+    Io(io::Error),
+    /// The controller answered with an error status. `retry_after` comes
+    /// from the `Retry-After` header, only its seconds form is understood.
+    Status {
+        status: StatusCode,
+        retry_after: Option<Duration>,
+    },
+    /// The controller answered `x-provision: rejected`.
+    ProvisionRejected(Rejection),
+    /// The controller asks for proof of the private key, but the client
+    /// does not have it.
+    NoProof,
+    /// The controller's challenge cannot be answered.
+    BadChallenge(String),
+    /// The controller answered without a session or stream URL.
+    NoSession,
+    /// Certificate pins are set and the controller's certificate matches
+    /// none, with the digest of the key it presented if any.
+    Unpinned(Option<String>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Http(e) => e.fmt(f),
+            Error::Io(e) => e.fmt(f),
+            Error::Status { status, .. } if is_refusal(*status) => {
+                write!(f, "controller refused the key ({})", status)
+            }
+            Error::Status { status, .. } => write!(f, "controller answered {}", status),
+            Error::ProvisionRejected(Rejection::Expired) => {
+                write!(
+                    f,
+                    "provision code has expired, create a new one in the dashboard"
+                )
+            }
+            Error::ProvisionRejected(Rejection::Invalid) => {
+                write!(f, "provision code is invalid, check it for typos")
+            }
+            Error::ProvisionRejected(Rejection::Other(reason)) => {
+                write!(f, "provision code was rejected: {}", reason)
+            }
+            Error::NoProof => write!(
+                f,
+                "controller asks for proof of the private key, which this client cannot read"
+            ),
+            Error::BadChallenge(reason) => write!(f, "cannot answer the challenge: {}", reason),
+            Error::NoSession => write!(f, "controller answered without a session or stream URL"),
+            Error::Unpinned(Some(digest)) => write!(
+                f,
+                "controller certificate matches no pin, its key is sha256//{}",
+                digest
+            ),
+            Error::Unpinned(None) => {
+                write!(f, "controller presented no certificate to check the pins")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// Whether the controller refused us with 401 or 403.
+    pub fn is_refusal(&self) -> bool {
+        matches!(self, Error::Status { status, .. } if is_refusal(*status))
+    }
+
+    /// Whether the stream request was turned down because the session is no
+    /// longer valid, with 401, 403 or 410, or there never was one.
+    pub fn is_session_expired(&self) -> bool {
+        match self {
+            Error::Status { status, .. } => is_refusal(*status) || *status == StatusCode::GONE,
+            Error::NoSession => true,
+            _ => false,
+        }
+    }
+}
+
+fn is_refusal(status: StatusCode) -> bool {
+    status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// What the controller returned from `/authorize`.
+#[derive(Clone, Debug, Default)]
+pub struct Authorization {
+    pub session: Option<String>,
+    pub url: Option<String>,
+    pub proxy: Option<String>,
+    pub network: Option<String>,
+    /// Overlay addresses, comma-separated, optionally in CIDR notation
+    pub ipaddr: Option<String>,
+    /// Prefix lengths for the `ipaddr` entries without one
+    pub prefix: Option<String>,
+    /// Only set when a provision code was sent
+    pub provision: Option<Provision>,
+    /// Control-stream format the controller agreed on
+    pub protocol: u32,
+    /// How the controller wants to serve the stream, `None` for old
+    /// controllers
+    pub transport: Option<Transport>,
+    /// The ed25519 key the controller signs the stream with
+    pub signing_key: Option<[u8; 32]>,
+    /// How often the controller sends a heartbeat, `None` when it does not
+    /// promise any
+    pub heartbeat: Option<Duration>,
+}
+
+/// What the client can do with the control stream, for the controller to
+/// pick from.
+#[derive(Clone, Copy, Debug)]
+pub struct Offer<'a> {
+    /// Ways to read the stream
+    pub transports: &'a [Transport],
+    /// The heartbeat interval the client asks for, `None` for none
+    pub heartbeat: Option<Duration>,
+}
+
+/// What came back from the controller: status, headers and body.
+struct Response {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Box<dyn Read + Send>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<String> {
+        self.headers
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .map(String::from)
+    }
+}
+
+fn check_status(response: Response) -> Result<Response, Error> {
+    let status = response.status;
+    if !status.is_client_error() && !status.is_server_error() {
+        return Ok(response);
+    }
+    Err(status_error(status, &response.headers))
+}
+
+/// An error answer, for reqwest and the WebSocket handshake alike.
+pub(crate) fn status_error(status: StatusCode, headers: &HeaderMap) -> Error {
+    let retry_after = headers
+        .get("retry-after")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs);
+    Error::Status {
+        status,
+        retry_after,
+    }
+}
+
+pub fn authorize(
+    server: &str,
+    pubkey: Option<[u8; 32]>,
+    listen_port: Option<u16>,
+    provision_code: Option<&str>,
+    offer: Offer,
+    secret: Option<&StaticSecret>,
+    tls: &Tls,
+) -> Result<Authorization, Error> {
+    let url = format!("{}/authorize", server);
+
+    // Create a vector to hold headers
+    let offered: Vec<String> = offer.transports.iter().map(Transport::to_string).collect();
+    let mut headers = vec![
+        ("User-Agent", "sitepi".to_string()),
+        ("PROTOCOL-VERSION", PROTOCOL_VERSION.to_string()),
+        ("TRANSPORT", offered.join(",")),
+    ];
+    if let Some(interval) = offer.heartbeat {
+        headers.push(("HEARTBEAT", interval.as_secs().max(1).to_string()));
+    }
+
+    // Add headers conditionally
+    if let Some(key) = pubkey {
+        headers.push(("PUBKEY", BASE64.encode(key)));
+    }
+    if let Some(port) = listen_port {
+        headers.push(("LISTEN-PORT", port.to_string()));
+    }
+    if let Some(code) = provision_code {
+        headers.push(("PROVISION-CODE", code.to_string()));
+    }
+
+    let mut response = post(&url, &headers, tls)?;
+
+    // The controller wants proof that we hold the private key
+    if response.status == StatusCode::UNAUTHORIZED {
+        if let Some(challenge) = response.header("x-challenge") {
+            let Some(secret) = secret else {
+                return Err(Error::NoProof);
+            };
+            let controller_key = response.header("x-controller-key").unwrap_or_default();
+            let proof = prove(secret, &challenge, &controller_key)?;
+            headers.push(("CHALLENGE", challenge));
+            headers.push(("PROOF", proof));
+            response = post(&url, &headers, tls)?;
+        }
+    }
+
+    // A rejection usually comes with a 4xx status, look at it first
+    let provision = match response.header("x-provision").as_deref() {
+        Some("bound") => Some(Provision::Bound),
+        Some("pending") => Some(Provision::Pending),
+        Some("rejected") => {
+            let rejection = match response.header("x-provision-error").as_deref() {
+                Some("expired") => Rejection::Expired,
+                Some("invalid") => Rejection::Invalid,
+                reason => Rejection::Other(reason.unwrap_or("no reason given").to_string()),
+            };
+            return Err(Error::ProvisionRejected(rejection));
+        }
+        _ => None,
+    };
+    let response = check_status(response)?;
+    let protocol = response
+        .header("x-protocol")
+        .and_then(|value| value.trim().parse().ok())
+        .filter(|version| (1..=PROTOCOL_VERSION).contains(version))
+        .unwrap_or(1);
+    let transport = response
+        .header("x-transport")
+        .and_then(|value| value.trim().parse().ok())
+        .filter(|transport| offer.transports.contains(transport));
+    let session = response.header("x-session");
+    let url = response.header("x-url");
+    // Only a code waiting for approval comes without a stream to follow
+    if provision != Some(Provision::Pending) && (session.is_none() || url.is_none()) {
+        return Err(Error::NoSession);
+    }
+
+    Ok(Authorization {
+        session,
+        url,
+        proxy: response.header("x-proxy"),
+        network: response.header("x-network"),
+        ipaddr: response.header("x-ipaddr"),
+        prefix: response.header("x-prefix"),
+        provision,
+        protocol,
+        transport,
+        signing_key: response
+            .header("x-signing-key")
+            .and_then(|value| BASE64.decode(value.trim()).ok())
+            .and_then(|key| key.try_into().ok()),
+        heartbeat: offer
+            .heartbeat
+            .and(response.header("x-heartbeat"))
+            .and_then(|value| value.trim().parse().ok())
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+    })
+}
+
+fn post(url: &str, headers: &[(&'static str, String)], tls: &Tls) -> Result<Response, Error> {
+    // The blocking client's default timeout
+    send(
+        Method::POST,
+        url,
+        headers,
+        None,
+        Duration::from_secs(30),
+        tls,
+    )
+}
+
+/// Sends a request, `timeout` applies to connecting and to every read. With
+/// pins set it goes over a connection whose certificate was checked first.
+fn send(
+    method: Method,
+    url: &str,
+    headers: &[(&'static str, String)],
+    proxy: Option<&str>,
+    timeout: Duration,
+    tls: &Tls,
+) -> Result<Response, Error> {
+    if tls.pinned() {
+        let (status, headers, body) =
+            transport::request(method.as_str(), url, headers, proxy, timeout, tls)?;
+        return Ok(Response {
+            status,
+            headers,
+            body,
+        });
+    }
+
+    // The blocking client applies its timeout to every read of the body
+    let mut builder = tls
+        .client()
+        .timeout(timeout)
+        .tcp_keepalive(Some(Duration::from_secs(24)));
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    let mut request = builder.build()?.request(method, url);
+    // Apply headers to the request
+    for (name, value) in headers {
+        request = request.header(*name, value);
+    }
+    let response = request.send()?;
+    Ok(Response {
+        status: response.status(),
+        headers: response.headers().clone(),
+        body: Box::new(response),
+    })
+}
+
+/// Answers an `x-challenge`: HMAC-SHA256 over the challenge and both public
+/// keys, keyed with the X25519 secret shared with the controller. Only the
+/// holder of the interface key can compute it, and it is no good for
+/// another controller key or challenge.
+fn prove(secret: &StaticSecret, challenge: &str, controller_key: &str) -> Result<String, Error> {
+    let bad = |reason: &str| Error::BadChallenge(reason.to_string());
+    let nonce = BASE64
+        .decode(challenge.trim())
+        .map_err(|_| bad("challenge is not base64"))?;
+    let controller_key: [u8; 32] = BASE64
+        .decode(controller_key.trim())
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| bad("controller key is not a base64 X25519 key"))?;
+    let controller_key = PublicKey::from(controller_key);
+
+    let shared = secret.diffie_hellman(&controller_key);
+    // A low-order controller key would make the secret guessable
+    if !shared.was_contributory() {
+        return Err(bad("controller key is weak"));
+    }
+    let mut mac = Hmac::<Sha256>::new_from_slice(shared.as_bytes())
+        .map_err(|_| bad("shared secret rejected"))?;
+    mac.update(b"sitepi authorize");
+    mac.update(&nonce);
+    mac.update(PublicKey::from(secret).as_bytes());
+    mac.update(controller_key.as_bytes());
+    Ok(BASE64.encode(mac.finalize().into_bytes()))
+}
+
+/// Opens the control stream of an authorized session. Reads from the stream
+/// fail with a timeout after `read_timeout` without data, see [`is_timeout`],
+/// the stream stays usable.
+///
+/// `revision` is the last one applied, the controller then only sends what
+/// changed since, or a full listing when it cannot.
+pub fn connect(
+    session: &str,
+    url: &str,
+    proxy: Option<&str>,
+    revision: Option<u64>,
+    transport: Transport,
+    read_timeout: Duration,
+    tls: &Tls,
+) -> Result<Stream, Error> {
+    let mut headers = vec![
+        ("User-Agent", "sitepi".to_string()),
+        ("X-Session", session.to_string()),
+    ];
+    if let Some(revision) = revision {
+        headers.push(("X-Revision", revision.to_string()));
+    }
+    if transport == Transport::WebSocket {
+        return transport::websocket(url, &headers, proxy, read_timeout, tls);
+    }
+    if transport == Transport::Sse {
+        headers.push(("Accept", "text/event-stream".to_string()));
+    }
+
+    let response = check_status(send(Method::GET, url, &headers, proxy, read_timeout, tls)?)?;
+    let content_type = response.header("content-type");
+    if transport == Transport::Sse {
+        if content_type.is_some_and(|value| value.starts_with("text/event-stream")) {
+            return Ok(Stream::sse(response.body));
+        }
+        println!("Controller did not answer with an event stream, reading lines");
+    }
+    Ok(Stream::chunked(response.body))
+}
+
+/// Whether a read error from the stream is only the read timeout expiring.
+pub fn is_timeout(error: &io::Error) -> bool {
+    // Plain sockets, as under a WebSocket, report it as one of these. A
+    // signal cutting the read short is no different.
+    if matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+    ) {
+        return true;
+    }
+    error
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<reqwest::Error>())
+        .is_some_and(reqwest::Error::is_timeout)
+}
+
+/// Tells the controller the interface goes offline. Best effort with a short
+/// timeout, the client is shutting down anyway.
+pub fn offline(server: &str, session: &str, pubkey: [u8; 32], tls: &Tls) -> Result<(), Error> {
+    let headers = [
+        ("User-Agent", "sitepi".to_string()),
+        ("PUBKEY", BASE64.encode(pubkey)),
+        ("X-Session", session.to_string()),
+    ];
+    let url = format!("{}/offline", server);
+    check_status(send(
+        Method::POST,
+        &url,
+        &headers,
+        None,
+        Duration::from_secs(3),
+        tls,
+    )?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHALLENGE: &str = "bm9uY2UtMTIzNA==";
+    const CONTROLLER_KEY: &str = "B6N8vBQgk8i3VdwbEOhstCY3StFqqFPtC9/AsrhtHHw=";
+
+    fn bad_challenge(result: Result<String, Error>) -> Option<String> {
+        match result {
+            Err(Error::BadChallenge(reason)) => Some(reason),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn prove_known_answer() {
+        let secret = StaticSecret::from([5; 32]);
+        let proof = prove(&secret, CHALLENGE, CONTROLLER_KEY).unwrap();
+        assert_eq!(proof, "tQjLnWOG5ISf3MbR7XBRxWJnqRR4CdEDLQbmvo/QAc4=");
+        // Headers may come with whitespace around them
+        let padded = format!(" {} ", CONTROLLER_KEY);
+        assert_eq!(prove(&secret, CHALLENGE, &padded).unwrap(), proof);
+
+        // Bound to the challenge, the controller and the interface key
+        assert_ne!(prove(&secret, "AQID", CONTROLLER_KEY).unwrap(), proof);
+        let other = BASE64.encode(PublicKey::from(&StaticSecret::from([9; 32])).as_bytes());
+        assert_ne!(prove(&secret, CHALLENGE, &other).unwrap(), proof);
+        let secret = StaticSecret::from([6; 32]);
+        assert_ne!(prove(&secret, CHALLENGE, CONTROLLER_KEY).unwrap(), proof);
+    }
+
+    #[test]
+    fn prove_rejects_bad_challenges() {
+        let secret = StaticSecret::from([5; 32]);
+        let cases = [
+            ("!!!", CONTROLLER_KEY, "challenge is not base64"),
+            (CHALLENGE, "", "controller key is not a base64 X25519 key"),
+            (
+                CHALLENGE,
+                "AQID",
+                "controller key is not a base64 X25519 key",
+            ),
+            // The identity point gives an all-zero shared secret
+            (
+                CHALLENGE,
+                "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+                "controller key is weak",
+            ),
+        ];
+        for (challenge, controller_key, reason) in cases {
+            assert_eq!(
+                bad_challenge(prove(&secret, challenge, controller_key)),
+                Some(reason.to_string()),
+                "{:?}",
+                controller_key
+            );
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! Linux kernel WireGuard backend.
+//!
+//! The device is configured over the `wireguard` generic netlink family,
+//! addresses and routes over rtnetlink.
+
+use super::netlink;
+use super::{Backend, Peer, PeerStats, Stats};
+use ipnet::IpNet;
+use netlink_packet_core::{NetlinkMessage, NLM_F_DUMP};
+use netlink_packet_generic::GenlMessage;
+use netlink_packet_route::link::InfoKind;
+use netlink_packet_wireguard::{
+    WireguardAddressFamily, WireguardAllowedIp, WireguardAllowedIpAttr, WireguardAttribute,
+    WireguardCmd, WireguardMessage, WireguardPeer, WireguardPeerAttribute, WireguardPeerFlags,
+};
+use netlink_sys::protocols::NETLINK_GENERIC;
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+// Same MTU the shell client used
+const MTU: u32 = 1420;
+
+pub struct KernelBackend {
+    index: u32,
+    family: u16,
+}
+
+impl KernelBackend {
+    fn new(name: &str) -> io::Result<Self> {
+        let link = netlink::get_link(name)?;
+        if link.kind != Some(InfoKind::Wireguard) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a WireGuard interface", name),
+            ));
+        }
+        let family = netlink::family_id("wireguard").map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("WireGuard generic netlink family unavailable: {}", e),
+            )
+        })?;
+        Ok(KernelBackend {
+            index: link.index,
+            family,
+        })
+    }
+
+    fn request(
+        &self,
+        cmd: WireguardCmd,
+        attributes: Vec<WireguardAttribute>,
+        flags: u16,
+    ) -> io::Result<Vec<WireguardMessage>> {
+        let mut message = GenlMessage::from_payload(WireguardMessage { cmd, attributes });
+        message.set_resolved_family_id(self.family);
+        let mut message = NetlinkMessage::from(message);
+        message.header.flags = flags;
+        Ok(netlink::request(NETLINK_GENERIC, message)?
+            .into_iter()
+            .map(|reply| reply.payload)
+            .collect())
+    }
+
+    fn set_device(&self, mut attributes: Vec<WireguardAttribute>) -> io::Result<()> {
+        attributes.insert(0, WireguardAttribute::IfIndex(self.index));
+        self.request(WireguardCmd::SetDevice, attributes, 0)
+            .map(|_| ())
+    }
+}
+
+fn wg_peer(peer: &Peer) -> WireguardPeer {
+    let mut attributes = vec![
+        WireguardPeerAttribute::PublicKey(peer.public_key),
+        WireguardPeerAttribute::Flags(WireguardPeerFlags::ReplaceAllowedIps),
+        WireguardPeerAttribute::PersistentKeepalive(peer.keepalive),
+    ];
+    // All zeros clears a key set before
+    attributes.push(WireguardPeerAttribute::PresharedKey(
+        peer.preshared_key.unwrap_or_default(),
+    ));
+    if let Some(endpoint) = peer.endpoint {
+        attributes.push(WireguardPeerAttribute::Endpoint(endpoint));
+    }
+    attributes.push(WireguardPeerAttribute::AllowedIps(
+        peer.allowed_ips
+            .iter()
+            .map(|ip| {
+                let family = match ip {
+                    IpNet::V4(_) => WireguardAddressFamily::Ipv4,
+                    IpNet::V6(_) => WireguardAddressFamily::Ipv6,
+                };
+                WireguardAllowedIp(vec![
+                    WireguardAllowedIpAttr::Family(family),
+                    WireguardAllowedIpAttr::IpAddr(ip.network()),
+                    WireguardAllowedIpAttr::Cidr(ip.prefix_len()),
+                ])
+            })
+            .collect(),
+    ));
+    WireguardPeer(attributes)
+}
+
+fn peer_stats(peer: &WireguardPeer) -> Option<PeerStats> {
+    let mut stats = PeerStats {
+        public_key: [0; 32],
+        endpoint: None,
+        allowed_ips: Vec::new(),
+        rx_bytes: 0,
+        tx_bytes: 0,
+        last_handshake: None,
+    };
+    let mut has_key = false;
+    for attr in peer.iter() {
+        match attr {
+            WireguardPeerAttribute::PublicKey(key) => {
+                stats.public_key = *key;
+                has_key = true;
+            }
+            WireguardPeerAttribute::Endpoint(endpoint) => stats.endpoint = Some(*endpoint),
+            WireguardPeerAttribute::RxBytes(bytes) => stats.rx_bytes = *bytes,
+            WireguardPeerAttribute::TxBytes(bytes) => stats.tx_bytes = *bytes,
+            WireguardPeerAttribute::LastHandshake(time) if time.seconds > 0 => {
+                stats.last_handshake = Some(
+                    SystemTime::UNIX_EPOCH
+                        + Duration::new(time.seconds as u64, time.nano_seconds as u32),
+                );
+            }
+            WireguardPeerAttribute::AllowedIps(ips) => {
+                for ip in ips {
+                    let addr = ip.iter().find_map(|attr| match attr {
+                        WireguardAllowedIpAttr::IpAddr(addr) => Some(*addr),
+                        _ => None,
+                    });
+                    let cidr = ip.iter().find_map(|attr| match attr {
+                        WireguardAllowedIpAttr::Cidr(cidr) => Some(*cidr),
+                        _ => None,
+                    });
+                    if let (Some(addr), Some(cidr)) = (addr, cidr) {
+                        if let Ok(net) = IpNet::new(addr, cidr) {
+                            stats.allowed_ips.push(net);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    has_key.then_some(stats)
+}
+
+impl Backend for KernelBackend {
+    fn open(name: &str) -> io::Result<Self> {
+        KernelBackend::new(name)
+    }
+
+    fn create(name: &str) -> io::Result<Self> {
+        // The kernel loads the wireguard module on demand when we are root
+        netlink::create_link(name, InfoKind::Wireguard).map_err(|e| {
+            if e.raw_os_error() == Some(libc::EOPNOTSUPP) {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "WireGuard kernel module is not available",
+                )
+            } else {
+                e
+            }
+        })?;
+        let backend = KernelBackend::new(name)?;
+        netlink::set_mtu(backend.index, MTU)?;
+        Ok(backend)
+    }
+
+    fn set_key(&self, private_key: &[u8; 32], listen_port: u16) -> io::Result<()> {
+        self.set_device(vec![
+            WireguardAttribute::PrivateKey(*private_key),
+            WireguardAttribute::ListenPort(listen_port),
+        ])
+    }
+
+    fn set_peer(&self, peer: &Peer) -> io::Result<()> {
+        self.set_device(vec![WireguardAttribute::Peers(vec![wg_peer(peer)])])
+    }
+
+    fn remove_peer(&self, public_key: &[u8; 32]) -> io::Result<()> {
+        self.set_device(vec![WireguardAttribute::Peers(vec![WireguardPeer(vec![
+            WireguardPeerAttribute::PublicKey(*public_key),
+            WireguardPeerAttribute::Flags(WireguardPeerFlags::RemoveMe),
+        ])])])
+    }
+
+    fn set_addresses(&self, addrs: &[IpNet]) -> io::Result<()> {
+        netlink::set_addresses(self.index, addrs)
+    }
+
+    fn add_route(&self, dest: IpNet, next_hop: IpAddr) -> io::Result<()> {
+        netlink::add_route(self.index, dest, next_hop)
+    }
+
+    fn del_route(&self, dest: IpNet, next_hop: IpAddr) -> io::Result<()> {
+        netlink::del_route(self.index, dest, next_hop)
+    }
+
+    fn up(&self) -> io::Result<()> {
+        netlink::set_up(self.index, true)
+    }
+
+    fn down(&self) -> io::Result<()> {
+        netlink::set_up(self.index, false)
+    }
+
+    fn stats(&self) -> io::Result<Stats> {
+        let replies = self.request(
+            WireguardCmd::GetDevice,
+            vec![WireguardAttribute::IfIndex(self.index)],
+            NLM_F_DUMP,
+        )?;
+
+        let mut stats = Stats {
+            public_key: [0; 32],
+            listen_port: 0,
+            peers: Vec::new(),
+        };
+        // Big devices come back split over several messages
+        for attr in replies.iter().flat_map(|reply| reply.attributes.iter()) {
+            match attr {
+                WireguardAttribute::PublicKey(key) => stats.public_key = *key,
+                WireguardAttribute::ListenPort(port) => stats.listen_port = *port,
+                WireguardAttribute::Peers(peers) => {
+                    for peer in peers {
+                        let Some(peer) = peer_stats(peer) else {
+                            continue;
+                        };
+                        // A peer split across messages repeats its key
+                        match stats
+                            .peers
+                            .iter_mut()
+                            .find(|p| p.public_key == peer.public_key)
+                        {
+                            Some(known) => known.allowed_ips.extend(peer.allowed_ips),
+                            None => stats.peers.push(peer),
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(stats)
+    }
+}
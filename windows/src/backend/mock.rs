@@ -0,0 +1,132 @@
+//! In-memory backend that records what the client asked for.
+
+use super::{Backend, Peer, PeerStats, Stats};
+use ipnet::IpNet;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Clone, Debug, Default)]
+pub struct MockState {
+    pub name: String,
+    pub private_key: [u8; 32],
+    pub listen_port: u16,
+    pub peers: Vec<Peer>,
+    pub addresses: Vec<IpNet>,
+    pub routes: Vec<(IpNet, IpAddr)>,
+    pub up: bool,
+    /// Makes `set_addresses` fail, to test error paths
+    pub fail_addresses: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct MockBackend {
+    state: Mutex<MockState>,
+}
+
+impl MockBackend {
+    pub fn new(name: &str) -> Self {
+        MockBackend {
+            state: Mutex::new(MockState {
+                name: name.to_string(),
+                ..MockState::default()
+            }),
+        }
+    }
+
+    /// Gives access to the recorded interface state.
+    pub fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Backend for MockBackend {
+    fn open(name: &str) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("mock interface {} does not exist", name),
+        ))
+    }
+
+    fn create(name: &str) -> io::Result<Self> {
+        Ok(MockBackend::new(name))
+    }
+
+    fn set_key(&self, private_key: &[u8; 32], listen_port: u16) -> io::Result<()> {
+        let mut state = self.state();
+        state.private_key = *private_key;
+        state.listen_port = listen_port;
+        Ok(())
+    }
+
+    fn set_peer(&self, peer: &Peer) -> io::Result<()> {
+        let mut state = self.state();
+        state.peers.retain(|p| p.public_key != peer.public_key);
+        state.peers.push(peer.clone());
+        Ok(())
+    }
+
+    fn remove_peer(&self, public_key: &[u8; 32]) -> io::Result<()> {
+        self.state().peers.retain(|p| &p.public_key != public_key);
+        Ok(())
+    }
+
+    fn set_addresses(&self, addrs: &[IpNet]) -> io::Result<()> {
+        let mut state = self.state();
+        if state.fail_addresses {
+            return Err(io::Error::other("set_addresses failed"));
+        }
+        state.addresses = addrs.to_vec();
+        Ok(())
+    }
+
+    fn add_route(&self, dest: IpNet, next_hop: IpAddr) -> io::Result<()> {
+        let mut state = self.state();
+        if state.routes.contains(&(dest, next_hop)) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "route exists"));
+        }
+        state.routes.push((dest, next_hop));
+        Ok(())
+    }
+
+    fn del_route(&self, dest: IpNet, next_hop: IpAddr) -> io::Result<()> {
+        let mut state = self.state();
+        let before = state.routes.len();
+        state.routes.retain(|r| r != &(dest, next_hop));
+        if state.routes.len() == before {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such route"));
+        }
+        Ok(())
+    }
+
+    fn up(&self) -> io::Result<()> {
+        self.state().up = true;
+        Ok(())
+    }
+
+    fn down(&self) -> io::Result<()> {
+        self.state().up = false;
+        Ok(())
+    }
+
+    fn stats(&self) -> io::Result<Stats> {
+        let state = self.state();
+        let secret = x25519_dalek::StaticSecret::from(state.private_key);
+        Ok(Stats {
+            public_key: x25519_dalek::PublicKey::from(&secret).to_bytes(),
+            listen_port: state.listen_port,
+            peers: state
+                .peers
+                .iter()
+                .map(|p| PeerStats {
+                    public_key: p.public_key,
+                    endpoint: p.endpoint,
+                    allowed_ips: p.allowed_ips.clone(),
+                    rx_bytes: 0,
+                    tx_bytes: 0,
+                    last_handshake: None,
+                })
+                .collect(),
+        })
+    }
+}
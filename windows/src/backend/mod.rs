@@ -0,0 +1,124 @@
+//! Tunnel backends.
+//!
+//! The authorize/stream/peer logic only talks to a [`Backend`], so it does not
+//! care whether the interface is a wireguard-nt adapter, a Linux kernel device,
+//! a userspace boringtun device or an in-memory mock.
+
+use ipnet::IpNet;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::SystemTime;
+
+#[cfg(target_os = "linux")]
+pub mod linux;
+pub mod mock;
+#[cfg(target_os = "linux")]
+mod netlink;
+#[cfg(windows)]
+pub mod nt;
+#[cfg(target_os = "linux")]
+pub mod userspace;
+
+/// A WireGuard peer as the client wants it configured.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Peer {
+    pub public_key: [u8; 32],
+    pub preshared_key: Option<[u8; 32]>,
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<IpNet>,
+    pub keepalive: u16,
+}
+
+/// Runtime counters of one peer as reported by the backend.
+#[derive(Clone, Debug)]
+pub struct PeerStats {
+    pub public_key: [u8; 32],
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<IpNet>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub last_handshake: Option<SystemTime>,
+}
+
+/// Interface state as reported by the backend.
+#[derive(Clone, Debug)]
+pub struct Stats {
+    pub public_key: [u8; 32],
+    pub listen_port: u16,
+    pub peers: Vec<PeerStats>,
+}
+
+pub trait Backend: Send + Sync {
+    /// Opens an existing interface.
+    fn open(name: &str) -> io::Result<Self>
+    where
+        Self: Sized;
+
+    /// Creates a new interface.
+    fn create(name: &str) -> io::Result<Self>
+    where
+        Self: Sized;
+
+    /// Sets the private key and listen port.
+    fn set_key(&self, private_key: &[u8; 32], listen_port: u16) -> io::Result<()>;
+
+    /// Adds or updates one peer, leaving the others alone.
+    fn set_peer(&self, peer: &Peer) -> io::Result<()>;
+
+    fn remove_peer(&self, public_key: &[u8; 32]) -> io::Result<()>;
+
+    /// Assigns the overlay addresses of the interface, replacing any others.
+    fn set_addresses(&self, addrs: &[IpNet]) -> io::Result<()>;
+
+    /// Routes `dest` via `next_hop` inside the overlay.
+    fn add_route(&self, dest: IpNet, next_hop: IpAddr) -> io::Result<()>;
+
+    fn del_route(&self, dest: IpNet, next_hop: IpAddr) -> io::Result<()>;
+
+    /// Brings the link up.
+    fn up(&self) -> io::Result<()>;
+
+    fn down(&self) -> io::Result<()>;
+
+    fn stats(&self) -> io::Result<Stats>;
+}
+
+/// Which WireGuard implementation drives the interface.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BackendKind {
+    /// The platform driver: wireguard-nt on Windows, the kernel module on Linux.
+    #[default]
+    Native,
+    /// boringtun on a TUN device, for hosts without the kernel module.
+    Userspace,
+}
+
+/// Opens the interface with the selected backend, creating it when it does
+/// not exist yet. The flag is `true` when the interface was created.
+pub fn open_or_create(name: &str, kind: BackendKind) -> io::Result<(Box<dyn Backend>, bool)> {
+    match kind {
+        #[cfg(windows)]
+        BackendKind::Native => open_or_create_with::<nt::NtBackend>(name),
+        #[cfg(target_os = "linux")]
+        BackendKind::Native => open_or_create_with::<linux::KernelBackend>(name),
+        #[cfg(target_os = "linux")]
+        BackendKind::Userspace => open_or_create_with::<userspace::UserspaceBackend>(name),
+        #[allow(unreachable_patterns)]
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("no {:?} tunnel backend for {} on this platform", kind, name),
+        )),
+    }
+}
+
+pub fn open_or_create_with<B: Backend + 'static>(
+    name: &str,
+) -> io::Result<(Box<dyn Backend>, bool)> {
+    match B::open(name) {
+        Ok(backend) => Ok((Box::new(backend), false)),
+        Err(_) => {
+            println!("{} not found, initialize it", name);
+            B::create(name).map(|backend| (Box::new(backend) as Box<dyn Backend>, true))
+        }
+    }
+}
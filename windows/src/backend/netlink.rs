@@ -0,0 +1,277 @@
+//! Minimal blocking netlink plumbing for the Linux backends.
+//!
+//! Every request opens its own socket, so callers never see replies that
+//! belong to somebody else and the backends need no locking.
+
+use ipnet::IpNet;
+use netlink_packet_core::{
+    NetlinkDeserializable, NetlinkMessage, NetlinkPayload, NetlinkSerializable, NLM_F_ACK,
+    NLM_F_CREATE, NLM_F_DUMP, NLM_F_EXCL, NLM_F_REPLACE, NLM_F_REQUEST,
+};
+use netlink_packet_generic::ctrl::nlas::GenlCtrlAttrs;
+use netlink_packet_generic::ctrl::{GenlCtrl, GenlCtrlCmd};
+use netlink_packet_generic::GenlMessage;
+use netlink_packet_route::address::{AddressAttribute, AddressMessage};
+use netlink_packet_route::link::{InfoKind, LinkAttribute, LinkFlags, LinkInfo, LinkMessage};
+use netlink_packet_route::route::{
+    RouteAddress, RouteAttribute, RouteHeader, RouteMessage, RouteProtocol, RouteScope, RouteType,
+};
+use netlink_packet_route::{AddressFamily, RouteNetlinkMessage};
+use netlink_sys::{protocols, Socket, SocketAddr};
+use std::fmt::Debug;
+use std::io;
+use std::net::IpAddr;
+
+/// Sends one request and collects every reply up to the final ACK or DONE.
+pub fn request<I>(protocol: isize, mut message: NetlinkMessage<I>) -> io::Result<Vec<I>>
+where
+    I: NetlinkSerializable + NetlinkDeserializable + Debug,
+{
+    let mut socket = Socket::new(protocol)?;
+    socket.bind_auto()?;
+    socket.connect(&SocketAddr::new(0, 0))?;
+
+    message.header.flags |= NLM_F_REQUEST | NLM_F_ACK;
+    message.header.sequence_number = 1;
+    message.finalize();
+    let mut buf = vec![0; message.buffer_len()];
+    message.serialize(&mut buf);
+    socket.send(&buf, 0)?;
+
+    let mut replies = Vec::new();
+    loop {
+        let (data, _) = socket.recv_from_full()?;
+        let mut offset = 0;
+        while offset < data.len() {
+            let reply = NetlinkMessage::<I>::deserialize(&data[offset..])
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            let length = reply.header.length as usize;
+            if length == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "zero-length netlink message",
+                ));
+            }
+            // Messages are padded to 4 bytes
+            offset += (length + 3) & !3;
+
+            match reply.payload {
+                NetlinkPayload::Done(_) => return Ok(replies),
+                NetlinkPayload::Error(e) if e.code.is_none() => return Ok(replies),
+                NetlinkPayload::Error(e) => return Err(e.to_io()),
+                NetlinkPayload::InnerMessage(inner) => replies.push(inner),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn route_request(message: RouteNetlinkMessage, flags: u16) -> io::Result<Vec<RouteNetlinkMessage>> {
+    let mut message = NetlinkMessage::from(message);
+    message.header.flags = flags;
+    request(protocols::NETLINK_ROUTE, message)
+}
+
+/// Resolves the id of a generic netlink family such as `wireguard`.
+pub fn family_id(name: &str) -> io::Result<u16> {
+    let message = NetlinkMessage::from(GenlMessage::from_payload(GenlCtrl {
+        cmd: GenlCtrlCmd::GetFamily,
+        nlas: vec![GenlCtrlAttrs::FamilyName(name.to_string())],
+    }));
+    request(protocols::NETLINK_GENERIC, message)?
+        .into_iter()
+        .flat_map(|reply| reply.payload.nlas)
+        .find_map(|nla| match nla {
+            GenlCtrlAttrs::FamilyId(id) => Some(id),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("generic netlink family {} not found", name),
+            )
+        })
+}
+
+/// A link found by name, with its kind when the kernel reports one.
+pub struct Link {
+    pub index: u32,
+    pub kind: Option<InfoKind>,
+}
+
+pub fn get_link(name: &str) -> io::Result<Link> {
+    let mut message = LinkMessage::default();
+    message
+        .attributes
+        .push(LinkAttribute::IfName(name.to_string()));
+    let reply = route_request(RouteNetlinkMessage::GetLink(message), 0)?
+        .into_iter()
+        .find_map(|reply| match reply {
+            RouteNetlinkMessage::NewLink(link) => Some(link),
+            _ => None,
+        })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", name)))?;
+
+    let kind = reply.attributes.iter().find_map(|attr| match attr {
+        LinkAttribute::LinkInfo(infos) => infos.iter().find_map(|info| match info {
+            LinkInfo::Kind(kind) => Some(kind.clone()),
+            _ => None,
+        }),
+        _ => None,
+    });
+    Ok(Link {
+        index: reply.header.index,
+        kind,
+    })
+}
+
+pub fn create_link(name: &str, kind: InfoKind) -> io::Result<()> {
+    let mut message = LinkMessage::default();
+    message
+        .attributes
+        .push(LinkAttribute::IfName(name.to_string()));
+    message
+        .attributes
+        .push(LinkAttribute::LinkInfo(vec![LinkInfo::Kind(kind)]));
+    route_request(
+        RouteNetlinkMessage::NewLink(message),
+        NLM_F_CREATE | NLM_F_EXCL,
+    )
+    .map(|_| ())
+}
+
+pub fn set_mtu(index: u32, mtu: u32) -> io::Result<()> {
+    let mut message = LinkMessage::default();
+    message.header.index = index;
+    message.attributes.push(LinkAttribute::Mtu(mtu));
+    route_request(RouteNetlinkMessage::SetLink(message), 0).map(|_| ())
+}
+
+pub fn set_up(index: u32, up: bool) -> io::Result<()> {
+    let mut message = LinkMessage::default();
+    message.header.index = index;
+    message.header.flags = if up {
+        LinkFlags::Up
+    } else {
+        LinkFlags::empty()
+    };
+    message.header.change_mask = LinkFlags::Up;
+    route_request(RouteNetlinkMessage::SetLink(message), 0).map(|_| ())
+}
+
+fn family(addr: &IpAddr) -> AddressFamily {
+    match addr {
+        IpAddr::V4(_) => AddressFamily::Inet,
+        IpAddr::V6(_) => AddressFamily::Inet6,
+    }
+}
+
+fn address_message(index: u32, addr: IpNet) -> AddressMessage {
+    let mut message = AddressMessage::default();
+    message.header.family = family(&addr.addr());
+    message.header.prefix_len = addr.prefix_len();
+    message.header.index = index;
+    message
+        .attributes
+        .push(AddressAttribute::Local(addr.addr()));
+    message
+        .attributes
+        .push(AddressAttribute::Address(addr.addr()));
+    message
+}
+
+/// Lists the addresses assigned to the link.
+pub fn addresses(index: u32) -> io::Result<Vec<IpNet>> {
+    let replies = route_request(
+        RouteNetlinkMessage::GetAddress(AddressMessage::default()),
+        NLM_F_DUMP,
+    )?;
+    Ok(replies
+        .into_iter()
+        .filter_map(|reply| match reply {
+            RouteNetlinkMessage::NewAddress(addr) if addr.header.index == index => {
+                let prefix_len = addr.header.prefix_len;
+                addr.attributes.into_iter().find_map(|attr| match attr {
+                    AddressAttribute::Address(ip) => IpNet::new(ip, prefix_len).ok(),
+                    _ => None,
+                })
+            }
+            _ => None,
+        })
+        .collect())
+}
+
+pub fn add_address(index: u32, addr: IpNet) -> io::Result<()> {
+    route_request(
+        RouteNetlinkMessage::NewAddress(address_message(index, addr)),
+        NLM_F_CREATE | NLM_F_REPLACE,
+    )
+    .map(|_| ())
+}
+
+pub fn del_address(index: u32, addr: IpNet) -> io::Result<()> {
+    route_request(
+        RouteNetlinkMessage::DelAddress(address_message(index, addr)),
+        0,
+    )
+    .map(|_| ())
+}
+
+/// Makes the link carry exactly `addrs`, leaving addresses that are already
+/// right and IPv6 link-local addresses untouched.
+pub fn set_addresses(index: u32, addrs: &[IpNet]) -> io::Result<()> {
+    let current = addresses(index)?;
+    let stale = current.iter().filter(|addr| {
+        let link_local =
+            matches!(addr, IpNet::V6(v6) if v6.addr().segments()[0] & 0xffc0 == 0xfe80);
+        !addrs.contains(addr) && !link_local
+    });
+    for addr in stale {
+        del_address(index, *addr)?;
+    }
+    for addr in addrs.iter().filter(|addr| !current.contains(addr)) {
+        add_address(index, *addr)?;
+    }
+    Ok(())
+}
+
+fn route_message(index: u32, dest: IpNet, next_hop: IpAddr) -> RouteMessage {
+    let mut message = RouteMessage::default();
+    message.header.address_family = family(&dest.addr());
+    message.header.destination_prefix_length = dest.prefix_len();
+    message.header.table = RouteHeader::RT_TABLE_MAIN;
+    message.header.protocol = RouteProtocol::Static;
+    message.header.scope = RouteScope::Universe;
+    message.header.kind = RouteType::Unicast;
+    message
+        .attributes
+        .push(RouteAttribute::Destination(RouteAddress::from(
+            dest.network(),
+        )));
+    message
+        .attributes
+        .push(RouteAttribute::Gateway(RouteAddress::from(next_hop)));
+    message.attributes.push(RouteAttribute::Oif(index));
+    message
+}
+
+/// Adds or replaces the route to `dest` via `next_hop` on the link.
+pub fn add_route(index: u32, dest: IpNet, next_hop: IpAddr) -> io::Result<()> {
+    route_request(
+        RouteNetlinkMessage::NewRoute(route_message(index, dest, next_hop)),
+        NLM_F_CREATE | NLM_F_REPLACE,
+    )
+    .map(|_| ())
+}
+
+pub fn del_route(index: u32, dest: IpNet, next_hop: IpAddr) -> io::Result<()> {
+    route_request(
+        RouteNetlinkMessage::DelRoute(route_message(index, dest, next_hop)),
+        0,
+    )
+    .map(|_| ())
+    .map_err(|e| match e.raw_os_error() {
+        Some(libc::ESRCH) => io::Error::new(io::ErrorKind::NotFound, "no such route"),
+        _ => e,
+    })
+}
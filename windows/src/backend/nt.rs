@@ -0,0 +1,398 @@
+//! wireguard-nt backend for Windows.
+//!
+//! The wireguard-nt crate only sets whole configurations, always with
+//! `WIREGUARD_INTERFACE_REPLACE_PEERS`. The key and single peers go to
+//! `WireGuardSetConfiguration` directly instead, without that flag, so the
+//! driver leaves the other peers and their sessions alone.
+
+use super::{Backend, Peer, PeerStats, Stats};
+use ipnet::IpNet;
+use std::io;
+use std::mem::size_of;
+use std::net::{IpAddr, SocketAddr};
+
+use winapi::shared::netioapi::{
+    CreateIpForwardEntry2, DeleteIpForwardEntry2, DeleteUnicastIpAddressEntry, FreeMibTable,
+    GetUnicastIpAddressTable, InitializeIpForwardEntry, MIB_IPFORWARD_ROW2,
+    PMIB_UNICASTIPADDRESS_TABLE,
+};
+use winapi::shared::winerror::{ERROR_NOT_FOUND, ERROR_OBJECT_ALREADY_EXISTS, ERROR_SUCCESS};
+use winapi::shared::ws2def::{AF_INET, AF_INET6, AF_UNSPEC};
+use winapi::shared::ws2ipdef::SOCKADDR_INET;
+
+pub struct NtBackend {
+    wireguard: wireguard_nt::Wireguard,
+    name: String,
+    adapter: wireguard_nt::Adapter,
+}
+
+// Flags and structs of wireguard.h, laid out as WireGuardSetConfiguration
+// expects them: the interface, then each peer followed by its allowed IPs
+const INTERFACE_HAS_PRIVATE_KEY: i32 = 1 << 1;
+const INTERFACE_HAS_LISTEN_PORT: i32 = 1 << 2;
+const PEER_HAS_PUBLIC_KEY: i32 = 1 << 0;
+const PEER_HAS_PRESHARED_KEY: i32 = 1 << 1;
+const PEER_HAS_PERSISTENT_KEEPALIVE: i32 = 1 << 2;
+const PEER_HAS_ENDPOINT: i32 = 1 << 3;
+const PEER_REPLACE_ALLOWED_IPS: i32 = 1 << 5;
+const PEER_REMOVE: i32 = 1 << 6;
+
+#[repr(C, align(8))]
+#[derive(Clone, Copy)]
+struct WgInterface {
+    flags: i32,
+    listen_port: u16,
+    private_key: [u8; 32],
+    public_key: [u8; 32],
+    peers_count: u32,
+}
+
+#[repr(C, align(8))]
+#[derive(Clone, Copy)]
+struct WgPeer {
+    flags: i32,
+    reserved: u32,
+    public_key: [u8; 32],
+    preshared_key: [u8; 32],
+    persistent_keepalive: u16,
+    endpoint: SOCKADDR_INET,
+    tx_bytes: u64,
+    rx_bytes: u64,
+    last_handshake: u64,
+    allowed_ips_count: u32,
+}
+
+#[repr(C, align(8))]
+struct WgAllowedIp {
+    address: [u8; 16],
+    address_family: u16,
+    cidr: u8,
+}
+
+// The sizes wireguard.h has, and multiples of 8 so every struct in the
+// buffer stays aligned
+const _: () = assert!(size_of::<WgInterface>() == 80);
+const _: () = assert!(size_of::<WgPeer>() == 136);
+const _: () = assert!(size_of::<WgAllowedIp>() == 24);
+
+fn load() -> io::Result<wireguard_nt::Wireguard> {
+    // Unsafe because we are loading an arbitrary dll file
+    unsafe { wireguard_nt::load_from_path("wireguard.dll") }
+        .map_err(|e| io::Error::other(format!("Failed to load wireguard dll: {}", e)))
+}
+
+fn nt_error(e: wireguard_nt::Error) -> io::Error {
+    match e {
+        wireguard_nt::Error::Driver(e) => e,
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+fn wg_interface(flags: i32) -> WgInterface {
+    WgInterface {
+        flags,
+        listen_port: 0,
+        private_key: [0; 32],
+        public_key: [0; 32],
+        peers_count: 0,
+    }
+}
+
+fn wg_peer(flags: i32, public_key: &[u8; 32]) -> WgPeer {
+    WgPeer {
+        flags: flags | PEER_HAS_PUBLIC_KEY,
+        reserved: 0,
+        public_key: *public_key,
+        preshared_key: [0; 32],
+        persistent_keepalive: 0,
+        endpoint: unsafe { std::mem::zeroed() },
+        tx_bytes: 0,
+        rx_bytes: 0,
+        last_handshake: 0,
+        allowed_ips_count: 0,
+    }
+}
+
+fn set_endpoint(peer: &mut WgPeer, endpoint: SocketAddr) {
+    unsafe {
+        match endpoint {
+            SocketAddr::V4(v4) => {
+                let addr = peer.endpoint.Ipv4_mut();
+                addr.sin_family = AF_INET as u16;
+                addr.sin_port = v4.port().to_be();
+                *addr.sin_addr.S_un.S_addr_mut() = u32::from_ne_bytes(v4.ip().octets());
+            }
+            SocketAddr::V6(v6) => {
+                let addr = peer.endpoint.Ipv6_mut();
+                addr.sin6_family = AF_INET6 as u16;
+                addr.sin6_port = v6.port().to_be();
+                *addr.sin6_addr.u.Byte_mut() = v6.ip().octets();
+            }
+        }
+    }
+}
+
+fn wg_allowed_ip(ip: &IpNet) -> WgAllowedIp {
+    let (address, address_family) = match ip.trunc() {
+        IpNet::V4(v4) => {
+            let mut address = [0; 16];
+            address[..4].copy_from_slice(&v4.addr().octets());
+            (address, AF_INET as u16)
+        }
+        IpNet::V6(v6) => (v6.addr().octets(), AF_INET6 as u16),
+    };
+    WgAllowedIp {
+        address,
+        address_family,
+        cidr: ip.prefix_len(),
+    }
+}
+
+impl NtBackend {
+    fn new(wireguard: wireguard_nt::Wireguard, name: &str, adapter: wireguard_nt::Adapter) -> Self {
+        adapter.set_logging(wireguard_nt::AdapterLoggingLevel::OnWithPrefix);
+        NtBackend {
+            wireguard,
+            name: name.to_string(),
+            adapter,
+        }
+    }
+
+    /// One WireGuardSetConfiguration call for `interface` and at most one
+    /// peer with its allowed IPs.
+    fn configure(
+        &self,
+        interface: WgInterface,
+        peer: Option<(WgPeer, &[IpNet])>,
+    ) -> io::Result<()> {
+        let allowed_ips = peer.map_or(&[][..], |(_, ips)| ips);
+        let size = size_of::<WgInterface>()
+            + peer.map_or(0, |_| size_of::<WgPeer>())
+            + allowed_ips.len() * size_of::<WgAllowedIp>();
+        // u64 keeps the buffer aligned for the structs written into it
+        let mut buffer = vec![0u64; size / 8];
+        unsafe {
+            let mut at = buffer.as_mut_ptr().cast::<u8>();
+            at.cast::<WgInterface>().write(WgInterface {
+                peers_count: peer.is_some() as u32,
+                ..interface
+            });
+            at = at.add(size_of::<WgInterface>());
+            if let Some((peer, _)) = peer {
+                at.cast::<WgPeer>().write(WgPeer {
+                    allowed_ips_count: allowed_ips.len() as u32,
+                    ..peer
+                });
+                at = at.add(size_of::<WgPeer>());
+            }
+            for ip in allowed_ips {
+                at.cast::<WgAllowedIp>().write(wg_allowed_ip(ip));
+                at = at.add(size_of::<WgAllowedIp>());
+            }
+        }
+
+        // A handle of our own, the crate keeps the adapter's to itself
+        let name: Vec<u16> = self.name.encode_utf16().chain(Some(0)).collect();
+        let handle = unsafe { self.wireguard.WireGuardOpenAdapter(name.as_ptr()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let result = match unsafe {
+            self.wireguard
+                .WireGuardSetConfiguration(handle, buffer.as_ptr().cast(), size as u32)
+        } {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        };
+        unsafe { self.wireguard.WireGuardCloseAdapter(handle) };
+        result
+    }
+}
+
+impl Backend for NtBackend {
+    fn open(name: &str) -> io::Result<Self> {
+        let wireguard = load()?;
+        wireguard_nt::Adapter::open(&wireguard, name)
+            .map(|adapter| NtBackend::new(wireguard.clone(), name, adapter))
+            .map_err(nt_error)
+    }
+
+    fn create(name: &str) -> io::Result<Self> {
+        let wireguard = load()?;
+        wireguard_nt::Adapter::create(&wireguard, "SitePi", name, None)
+            .map(|adapter| NtBackend::new(wireguard.clone(), name, adapter))
+            .map_err(nt_error)
+    }
+
+    fn set_key(&self, private_key: &[u8; 32], listen_port: u16) -> io::Result<()> {
+        let interface = WgInterface {
+            listen_port,
+            private_key: *private_key,
+            ..wg_interface(INTERFACE_HAS_PRIVATE_KEY | INTERFACE_HAS_LISTEN_PORT)
+        };
+        self.configure(interface, None)
+    }
+
+    fn set_peer(&self, peer: &Peer) -> io::Result<()> {
+        // Without WIREGUARD_PEER_UPDATE a missing peer is added. The
+        // preshared key is always set, all zeros removes an earlier one.
+        let mut flags =
+            PEER_HAS_PRESHARED_KEY | PEER_HAS_PERSISTENT_KEEPALIVE | PEER_REPLACE_ALLOWED_IPS;
+        if peer.endpoint.is_some() {
+            flags |= PEER_HAS_ENDPOINT;
+        }
+        let mut wg = WgPeer {
+            preshared_key: peer.preshared_key.unwrap_or_default(),
+            persistent_keepalive: peer.keepalive,
+            ..wg_peer(flags, &peer.public_key)
+        };
+        if let Some(endpoint) = peer.endpoint {
+            set_endpoint(&mut wg, endpoint);
+        }
+        self.configure(wg_interface(0), Some((wg, &peer.allowed_ips)))
+    }
+
+    fn remove_peer(&self, public_key: &[u8; 32]) -> io::Result<()> {
+        let peer = wg_peer(PEER_REMOVE, public_key);
+        self.configure(wg_interface(0), Some((peer, &[])))
+    }
+
+    fn set_addresses(&self, addrs: &[IpNet]) -> io::Result<()> {
+        // set_default_route only adds, drop what the interface had before
+        remove_stale_addresses(self.adapter.get_luid(), addrs)?;
+        // It also routes every allowed IP of the peers it is given, the
+        // zero route included. Routes are the reconciler's business, give it
+        // none so it only assigns the addresses.
+        let interface = wireguard_nt::SetInterface {
+            listen_port: None,
+            public_key: None,
+            private_key: None,
+            peers: Vec::new(),
+        };
+        self.adapter
+            .set_default_route(addrs, &interface)
+            .map_err(nt_error)
+    }
+
+    fn add_route(&self, dest: IpNet, next_hop: IpAddr) -> io::Result<()> {
+        let row = route_row(self.adapter.get_luid(), dest, next_hop)?;
+        match unsafe { CreateIpForwardEntry2(&row) } {
+            ERROR_SUCCESS | ERROR_OBJECT_ALREADY_EXISTS => Ok(()),
+            result => Err(io::Error::from_raw_os_error(result as i32)),
+        }
+    }
+
+    fn del_route(&self, dest: IpNet, next_hop: IpAddr) -> io::Result<()> {
+        let row = route_row(self.adapter.get_luid(), dest, next_hop)?;
+        match unsafe { DeleteIpForwardEntry2(&row) } {
+            ERROR_SUCCESS => Ok(()),
+            ERROR_NOT_FOUND => Err(io::Error::new(io::ErrorKind::NotFound, "no such route")),
+            result => Err(io::Error::from_raw_os_error(result as i32)),
+        }
+    }
+
+    fn up(&self) -> io::Result<()> {
+        self.adapter.up().map_err(nt_error)
+    }
+
+    fn down(&self) -> io::Result<()> {
+        self.adapter.down().map_err(nt_error)
+    }
+
+    fn stats(&self) -> io::Result<Stats> {
+        let config = self.adapter.get_config();
+        Ok(Stats {
+            public_key: config.public_key,
+            listen_port: config.listen_port,
+            peers: config
+                .peers
+                .into_iter()
+                .map(|p| PeerStats {
+                    public_key: p.public_key,
+                    endpoint: Some(p.endpoint).filter(|e| !e.ip().is_unspecified()),
+                    allowed_ips: p.allowed_ips,
+                    rx_bytes: p.rx_bytes,
+                    tx_bytes: p.tx_bytes,
+                    last_handshake: p.last_handshake,
+                })
+                .collect(),
+        })
+    }
+}
+
+fn remove_stale_addresses(luid: u64, keep: &[IpNet]) -> io::Result<()> {
+    let mut table: PMIB_UNICASTIPADDRESS_TABLE = std::ptr::null_mut();
+    let result = unsafe { GetUnicastIpAddressTable(AF_UNSPEC as u16, &mut table) };
+    if result != ERROR_SUCCESS {
+        return Err(io::Error::from_raw_os_error(result as i32));
+    }
+
+    let mut result = Ok(());
+    unsafe {
+        let rows =
+            std::slice::from_raw_parts((*table).Table.as_ptr(), (*table).NumEntries as usize);
+        for row in rows.iter().filter(|row| row.InterfaceLuid.Value == luid) {
+            let addr = match *row.Address.si_family() as i32 {
+                AF_INET => IpAddr::from(row.Address.Ipv4().sin_addr.S_un.S_addr().to_ne_bytes()),
+                AF_INET6 => IpAddr::from(*row.Address.Ipv6().sin6_addr.u.Byte()),
+                _ => continue,
+            };
+            let kept = keep
+                .iter()
+                .any(|net| net.addr() == addr && net.prefix_len() == row.OnLinkPrefixLength);
+            // Leave the link-local addresses Windows assigns by itself
+            let link_local = match addr {
+                IpAddr::V4(v4) => v4.is_link_local(),
+                IpAddr::V6(v6) => v6.segments()[0] & 0xffc0 == 0xfe80,
+            };
+            if kept || link_local {
+                continue;
+            }
+            let err = DeleteUnicastIpAddressEntry(row);
+            if err != ERROR_SUCCESS {
+                result = Err(io::Error::from_raw_os_error(err as i32));
+            }
+        }
+        FreeMibTable(table as _);
+    }
+    result
+}
+
+// Routes are bound to the adapter LUID, the old MIB_IPFORWARDROW API would
+// need the interface index and only knows IPv4
+fn route_row(luid: u64, dest: IpNet, next_hop: IpAddr) -> io::Result<MIB_IPFORWARD_ROW2> {
+    unsafe {
+        let mut row = std::mem::zeroed::<MIB_IPFORWARD_ROW2>();
+        InitializeIpForwardEntry(&mut row);
+        row.InterfaceLuid.Value = luid;
+        row.DestinationPrefix.PrefixLength = dest.prefix_len();
+
+        match (dest.trunc(), next_hop) {
+            (IpNet::V4(dest), IpAddr::V4(next_hop)) => {
+                let prefix = row.DestinationPrefix.Prefix.Ipv4_mut();
+                prefix.sin_family = AF_INET as u16;
+                *prefix.sin_addr.S_un.S_addr_mut() = u32::from_ne_bytes(dest.addr().octets());
+
+                let hop = row.NextHop.Ipv4_mut();
+                hop.sin_family = AF_INET as u16;
+                *hop.sin_addr.S_un.S_addr_mut() = u32::from_ne_bytes(next_hop.octets());
+            }
+            (IpNet::V6(dest), IpAddr::V6(next_hop)) => {
+                let prefix = row.DestinationPrefix.Prefix.Ipv6_mut();
+                prefix.sin6_family = AF_INET6 as u16;
+                *prefix.sin6_addr.u.Byte_mut() = dest.addr().octets();
+
+                let hop = row.NextHop.Ipv6_mut();
+                hop.sin6_family = AF_INET6 as u16;
+                *hop.sin6_addr.u.Byte_mut() = next_hop.octets();
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} and next hop {} are different families", dest, next_hop),
+                ))
+            }
+        }
+        Ok(row)
+    }
+}
@@ -0,0 +1,330 @@
+//! Userspace WireGuard backend for hosts without the kernel module.
+//!
+//! The data plane is boringtun on top of a TUN device. It is configured
+//! through the standard cross-platform UAPI socket, so an interface run by
+//! another userspace implementation (wireguard-go) can be opened as well.
+//! Addresses and routes go over rtnetlink like for the kernel backend.
+//!
+//! A device started here is driven over a private socket pair instead:
+//! boringtun serving the socket itself also takes SIGINT and SIGTERM away
+//! from the client and stops the tunnel. The socket for `wg` is served by
+//! relaying to the private one.
+
+use super::netlink;
+use super::{Backend, Peer, PeerStats, Stats};
+use boringtun::device::{DeviceConfig, DeviceHandle};
+use ipnet::IpNet;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::os::fd::IntoRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+const SOCKET_DIR: &str = "/var/run/wireguard";
+
+// Same MTU the shell client used
+const MTU: u32 = 1420;
+
+pub struct UserspaceBackend {
+    socket: String,
+    index: u32,
+    // Set when boringtun runs inside this process
+    device: Option<Device>,
+}
+
+struct Device {
+    // The device lives as long as the handle does
+    _handle: DeviceHandle,
+    control: Arc<Mutex<BufReader<UnixStream>>>,
+}
+
+fn hex(key: &[u8; 32]) -> String {
+    key.iter().fold(String::with_capacity(64), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+fn unhex(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 {
+        return None;
+    }
+    let mut key = [0; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
+
+fn peer_config(command: &mut String, peer: &Peer) {
+    let _ = writeln!(command, "public_key={}", hex(&peer.public_key));
+    // All zeros clears a key set before
+    let _ = writeln!(
+        command,
+        "preshared_key={}",
+        hex(&peer.preshared_key.unwrap_or_default())
+    );
+    if let Some(endpoint) = peer.endpoint {
+        let _ = writeln!(command, "endpoint={}", endpoint);
+    }
+    let _ = writeln!(
+        command,
+        "persistent_keepalive_interval={}\nreplace_allowed_ips=true",
+        peer.keepalive
+    );
+    for ip in &peer.allowed_ips {
+        let _ = writeln!(command, "allowed_ip={}", ip.trunc());
+    }
+}
+
+fn empty_peer(public_key: [u8; 32]) -> PeerStats {
+    PeerStats {
+        public_key,
+        endpoint: None,
+        allowed_ips: Vec::new(),
+        rx_bytes: 0,
+        tx_bytes: 0,
+        last_handshake: None,
+    }
+}
+
+/// Writes one UAPI request and reads the reply, returning the lines before the
+/// errno and the errno.
+fn exchange(stream: &mut BufReader<UnixStream>, request: &str) -> io::Result<(Vec<String>, i32)> {
+    stream.get_mut().write_all(request.as_bytes())?;
+
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "UAPI reply without errno",
+            ));
+        }
+        let line = line.trim_end();
+        if let Some(errno) = line.strip_prefix("errno=") {
+            // The blank line closing the reply, the stream may be reused
+            stream.read_line(&mut String::new())?;
+            return Ok((lines, errno.parse().unwrap_or(libc::EIO)));
+        }
+        if !line.is_empty() {
+            lines.push(line.to_string());
+        }
+    }
+}
+
+/// Serves the UAPI socket of a device started here by passing each request
+/// on to its private socket.
+fn serve(listener: UnixListener, control: Arc<Mutex<BufReader<UnixStream>>>) {
+    for conn in listener.incoming().flatten() {
+        if let Err(e) = relay(conn, &control) {
+            println!("UAPI request failed: {}", e);
+        }
+    }
+}
+
+fn relay(conn: UnixStream, control: &Mutex<BufReader<UnixStream>>) -> io::Result<()> {
+    // A client that never finishes its request must not block the others
+    conn.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut reader = BufReader::new(conn.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    match request.trim_end() {
+        // boringtun answers a get without waiting for the blank line, it must
+        // not reach the private socket
+        "get=1" => {}
+        "set=1" => loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            request.push_str(&line);
+            if line.trim_end().is_empty() {
+                break;
+            }
+        },
+        _ => return writeln!(&conn, "errno={}\n", libc::EINVAL),
+    }
+
+    let (lines, errno) = exchange(&mut control.lock().unwrap(), &request)?;
+    let mut reply = String::new();
+    for line in lines {
+        let _ = writeln!(reply, "{}", line);
+    }
+    let _ = writeln!(reply, "errno={}\n", errno);
+    (&conn).write_all(reply.as_bytes())
+}
+
+impl UserspaceBackend {
+    fn new(name: &str, device: Option<Device>) -> io::Result<Self> {
+        let backend = UserspaceBackend {
+            socket: format!("{}/{}.sock", SOCKET_DIR, name),
+            index: netlink::get_link(name)?.index,
+            device,
+        };
+        // Fails early on a stale socket left behind by a dead process
+        backend.uapi("get=1\n")?;
+        Ok(backend)
+    }
+
+    /// Runs one UAPI command and returns the reply lines without the errno.
+    fn uapi(&self, command: &str) -> io::Result<Vec<String>> {
+        let (lines, errno) = match &self.device {
+            Some(device) => {
+                let request = if command.starts_with("get=") {
+                    command.to_string()
+                } else {
+                    format!("{}\n", command)
+                };
+                exchange(&mut device.control.lock().unwrap(), &request)?
+            }
+            None => {
+                let stream = UnixStream::connect(&self.socket)?;
+                exchange(&mut BufReader::new(stream), &format!("{}\n", command))?
+            }
+        };
+        match errno {
+            0 => Ok(lines),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
+
+impl Drop for UserspaceBackend {
+    fn drop(&mut self) {
+        if self.device.is_some() {
+            let _ = std::fs::remove_file(&self.socket);
+        }
+    }
+}
+
+impl Backend for UserspaceBackend {
+    fn open(name: &str) -> io::Result<Self> {
+        UserspaceBackend::new(name, None)
+    }
+
+    fn create(name: &str) -> io::Result<Self> {
+        let (control, device_end) = UnixStream::pair()?;
+        let config = DeviceConfig {
+            uapi_fd: device_end.into_raw_fd(),
+            ..DeviceConfig::default()
+        };
+        let handle = DeviceHandle::new(name, config)
+            .map_err(|e| io::Error::other(format!("Failed to start userspace WireGuard: {}", e)))?;
+        let control = Arc::new(Mutex::new(BufReader::new(control)));
+
+        let socket = format!("{}/{}.sock", SOCKET_DIR, name);
+        std::fs::create_dir_all(SOCKET_DIR)?;
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket)?;
+        let relay_control = Arc::clone(&control);
+        std::thread::spawn(move || serve(listener, relay_control));
+
+        let device = Device {
+            _handle: handle,
+            control,
+        };
+        let backend = UserspaceBackend::new(name, Some(device))?;
+        netlink::set_mtu(backend.index, MTU)?;
+        Ok(backend)
+    }
+
+    fn set_key(&self, private_key: &[u8; 32], listen_port: u16) -> io::Result<()> {
+        self.uapi(&format!(
+            "set=1\nprivate_key={}\nlisten_port={}\n",
+            hex(private_key),
+            listen_port
+        ))
+        .map(|_| ())
+    }
+
+    fn set_peer(&self, peer: &Peer) -> io::Result<()> {
+        // boringtun cannot modify a peer in place, and within one command
+        // the removal would carry over to the new peer section
+        self.remove_peer(&peer.public_key)?;
+        let mut command = String::from("set=1\n");
+        peer_config(&mut command, peer);
+        self.uapi(&command).map(|_| ())
+    }
+
+    fn remove_peer(&self, public_key: &[u8; 32]) -> io::Result<()> {
+        self.uapi(&format!(
+            "set=1\npublic_key={}\nremove=true\n",
+            hex(public_key)
+        ))
+        .map(|_| ())
+    }
+
+    fn set_addresses(&self, addrs: &[IpNet]) -> io::Result<()> {
+        netlink::set_addresses(self.index, addrs)
+    }
+
+    fn add_route(&self, dest: IpNet, next_hop: IpAddr) -> io::Result<()> {
+        netlink::add_route(self.index, dest, next_hop)
+    }
+
+    fn del_route(&self, dest: IpNet, next_hop: IpAddr) -> io::Result<()> {
+        netlink::del_route(self.index, dest, next_hop)
+    }
+
+    fn up(&self) -> io::Result<()> {
+        netlink::set_up(self.index, true)
+    }
+
+    fn down(&self) -> io::Result<()> {
+        netlink::set_up(self.index, false)
+    }
+
+    fn stats(&self) -> io::Result<Stats> {
+        let mut stats = Stats {
+            public_key: [0; 32],
+            listen_port: 0,
+            peers: Vec::new(),
+        };
+        // boringtun reports the time since the handshake, not the time of it
+        let elapsed_handshake = self.device.is_some();
+        let mut handshake_sec = 0;
+
+        for line in self.uapi("get=1\n")? {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let peer = stats.peers.last_mut();
+            match (key, peer) {
+                ("private_key", _) => {
+                    if let Some(private) = unhex(value) {
+                        let secret = x25519_dalek::StaticSecret::from(private);
+                        stats.public_key = x25519_dalek::PublicKey::from(&secret).to_bytes();
+                    }
+                }
+                ("own_public_key", _) => stats.public_key = unhex(value).unwrap_or_default(),
+                ("listen_port", _) => stats.listen_port = value.parse().unwrap_or(0),
+                ("public_key", _) => {
+                    if let Some(key) = unhex(value) {
+                        stats.peers.push(empty_peer(key));
+                    }
+                }
+                ("endpoint", Some(peer)) => peer.endpoint = value.parse().ok(),
+                ("allowed_ip", Some(peer)) => peer.allowed_ips.extend(value.parse::<IpNet>()),
+                ("rx_bytes", Some(peer)) => peer.rx_bytes = value.parse().unwrap_or(0),
+                ("tx_bytes", Some(peer)) => peer.tx_bytes = value.parse().unwrap_or(0),
+                ("last_handshake_time_sec", _) => handshake_sec = value.parse().unwrap_or(0),
+                ("last_handshake_time_nsec", Some(peer)) => {
+                    let time = Duration::new(handshake_sec, value.parse().unwrap_or(0));
+                    peer.last_handshake = if elapsed_handshake {
+                        SystemTime::now().checked_sub(time)
+                    } else if handshake_sec > 0 {
+                        Some(SystemTime::UNIX_EPOCH + time)
+                    } else {
+                        None
+                    };
+                }
+                _ => {}
+            }
+        }
+        Ok(stats)
+    }
+}
@@ -0,0 +1,1093 @@
+//! The client run loop: authorize, follow the control stream, retry.
+//!
+//! The connection moves between the phases Authorizing, Streaming,
+//! Backoff and Revoked. Retries back off exponentially with full jitter,
+//! 429 and 503 answers set the delay through `Retry-After`, both capped by
+//! `--max-backoff`, and a refused key stops the client. A dropped stream is
+//! resumed with the same session until the controller reports it expired.
+//! When the controller agreed to send heartbeats, a stream that stays silent
+//! past the idle timeout counts as dropped.
+
+use crate::api::{self, Authorization, Offer, Provision};
+use crate::backend::Backend;
+use crate::key::CONFIG_DIR;
+use crate::message::{self, ControlMessage};
+use crate::peers::Reconciler;
+use crate::status::{self, Status};
+use crate::tls::Tls;
+use crate::transport::Transport;
+use crate::{key, provision, routes, state};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ed25519_dalek::VerifyingKey;
+use ipnet::IpNet;
+use rand::Rng;
+use reqwest::StatusCode;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Settings of one client instance.
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub server: String,
+    pub interface: String,
+    pub provision_code: Option<String>,
+    /// Install routes for the subnets behind peers
+    pub route: bool,
+    /// What to undo on the interface when shutting down
+    pub teardown: Teardown,
+    /// Longest wait between reconnect attempts
+    pub max_backoff: Duration,
+    /// Reconnect when no message, heartbeats included, arrived for this
+    /// long. The controller is asked for a heartbeat every third of it, and
+    /// the deadline only applies when it agrees. `None` trusts the
+    /// connection until it fails.
+    pub idle_timeout: Option<Duration>,
+    /// How to read the control stream, `None` lets the controller pick
+    pub transport: Option<Transport>,
+    /// CA bundle, pins and client certificate for the controller
+    pub tls: Tls,
+}
+
+/// How much of the configuration is removed on shutdown, each level
+/// includes the ones before it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Teardown {
+    /// Leave everything in place, the next start sweeps stale routes.
+    None,
+    /// Remove the routes the client installed.
+    #[default]
+    Routes,
+    /// Also remove all peers.
+    Peers,
+    /// Also bring the interface down.
+    Down,
+}
+
+impl FromStr for Teardown {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Teardown::None),
+            "routes" => Ok(Teardown::Routes),
+            "peers" => Ok(Teardown::Peers),
+            "down" => Ok(Teardown::Down),
+            _ => Err(format!(
+                "unknown teardown '{}', expected none, routes, peers or down",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Teardown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Teardown::None => "none",
+            Teardown::Routes => "routes",
+            Teardown::Peers => "peers",
+            Teardown::Down => "down",
+        })
+    }
+}
+
+/// How often the interface is checked against the desired state between
+/// control messages.
+const RECONCILE_INTERVAL: u64 = 30;
+
+/// Stream reads wake up this often to look at the exit flag. It also bounds
+/// how long the controller may take to answer the stream request.
+const READ_TICK: Duration = Duration::from_secs(5);
+
+/// First retry delay, doubled per failure up to [`Options::max_backoff`].
+const BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// A stream that stayed up this long was healthy, the next failure starts
+/// the backoff over.
+const HEALTHY_STREAM: Duration = Duration::from_secs(60);
+
+/// Sleeps for `delay`, `false` when `exit` was set meanwhile.
+fn sleep(delay: Duration, exit: &AtomicBool) -> bool {
+    let mut left = delay;
+    while !exit.load(Ordering::Relaxed) {
+        let step = left.min(Duration::from_millis(100));
+        if step.is_zero() {
+            return true;
+        }
+        std::thread::sleep(step);
+        left -= step;
+    }
+    false
+}
+
+/// Where the client stands with the controller.
+enum Phase {
+    /// Registering the key and getting a session.
+    Authorizing,
+    /// Following the control stream of a session.
+    Streaming(Authorization),
+    /// Waiting before streaming again with the session, or authorizing anew
+    /// without one.
+    Backoff(Duration, Option<Authorization>),
+    /// The controller will not take us, retrying would not help.
+    Revoked(api::Error),
+}
+
+impl Phase {
+    fn name(&self) -> &'static str {
+        match self {
+            Phase::Authorizing => "authorizing",
+            Phase::Streaming(_) => "streaming",
+            Phase::Backoff(..) => "backoff",
+            Phase::Revoked(_) => "revoked",
+        }
+    }
+}
+
+/// Keeps the interface connected to the controller until `exit` is set,
+/// then shuts down as configured in [`Options::teardown`].
+pub fn run(
+    options: &Options,
+    adapter: &dyn Backend,
+    exit: &AtomicBool,
+) -> Result<(), Box<dyn Error>> {
+    let config = adapter.stats()?;
+
+    let provision_code = provision_code(CONFIG_DIR, options);
+
+    // Routes a crashed run left behind
+    routes::sweep(CONFIG_DIR, &options.interface, adapter);
+
+    let reconciler = Reconciler::new(CONFIG_DIR, &options.interface, options.route);
+    // Bring the mesh up without waiting for the controller
+    if let Some(snapshot) = state::load(CONFIG_DIR, &options.interface) {
+        println!(
+            "Restoring the last known state: {} peers",
+            snapshot.peers.len()
+        );
+        reconciler.restore(snapshot);
+        if let Err(e) = reconciler.reconcile(adapter) {
+            println!("Failed to restore the last known state: {}", e);
+        }
+    }
+    let mut client = Client {
+        options,
+        dir: CONFIG_DIR,
+        adapter,
+        reconciler: &reconciler,
+        exit,
+        pubkey: config.public_key,
+        secret: load_secret(&options.interface, &config.public_key),
+        controller_key: None,
+        listen_port: config.listen_port,
+        provision_code,
+        session: None,
+        sequence: None,
+        failures: 0,
+        status: Status::default(),
+        revision: None,
+    };
+    let done = AtomicBool::new(false);
+    let result = std::thread::scope(|scope| {
+        scope.spawn(|| watch(&reconciler, adapter, exit, &done));
+        let result = client.follow();
+        done.store(true, Ordering::Relaxed);
+        result
+    });
+    client.shutdown();
+    status::remove(&options.interface);
+    result
+}
+
+/// The provision code to send, `None` when the binding already spent it.
+fn provision_code(dir: &str, options: &Options) -> Option<String> {
+    let code = options.provision_code.as_ref()?;
+    let Some(mut binding) = provision::load(dir, &options.interface) else {
+        return Some(code.clone());
+    };
+    // Another code moves the interface to another network
+    if binding.spent(code) == Some(false) {
+        return Some(code.clone());
+    }
+    println!(
+        "Already provisioned into network {}, the provision code is not needed",
+        binding.network
+    );
+    // Older bindings do not say which code they spent, take this one
+    if binding.code.is_none() {
+        binding.code = Some(provision::digest(code));
+        if let Err(e) = provision::save(dir, &options.interface, &binding) {
+            println!("Failed to save the provisioning binding: {}", e);
+        }
+    }
+    None
+}
+
+/// Re-converges the interface periodically, e.g. after an external reset.
+fn watch(reconciler: &Reconciler, adapter: &dyn Backend, exit: &AtomicBool, done: &AtomicBool) {
+    loop {
+        for _ in 0..RECONCILE_INTERVAL {
+            std::thread::sleep(Duration::from_secs(1));
+            if exit.load(Ordering::Relaxed) || done.load(Ordering::Relaxed) {
+                return;
+            }
+        }
+        if let Err(e) = reconciler.reconcile(adapter) {
+            println!("Failed to check the interface: {}", e);
+        }
+    }
+}
+
+/// State of one [`run`].
+struct Client<'a> {
+    options: &'a Options,
+    /// Where the binding and the pinned key live
+    dir: &'a str,
+    adapter: &'a dyn Backend,
+    reconciler: &'a Reconciler,
+    exit: &'a AtomicBool,
+    pubkey: [u8; 32],
+    /// The private key, to answer the controller's challenge
+    secret: Option<StaticSecret>,
+    /// The pinned key stream messages must be signed with
+    controller_key: Option<[u8; 32]>,
+    listen_port: u16,
+    provision_code: Option<String>,
+    /// The last session the controller handed out
+    session: Option<String>,
+    /// Sequence number of the last signed line of the session
+    sequence: Option<u64>,
+    /// Failed attempts since the controller last answered properly
+    failures: u32,
+    /// What `sitepi --status` shows
+    status: Status,
+    /// Revision of the network state last applied from the stream. Not
+    /// kept across restarts, the restored state needs a full listing.
+    revision: Option<u64>,
+}
+
+impl Client<'_> {
+    fn exiting(&self) -> bool {
+        self.exit.load(Ordering::Relaxed)
+    }
+
+    /// Runs the connection state machine until `exit` is set or the
+    /// controller revokes us.
+    fn follow(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut phase = Phase::Authorizing;
+        while !self.exiting() {
+            self.enter(&phase);
+            phase = match phase {
+                Phase::Authorizing => match self.do_authorize() {
+                    Ok(Some(auth)) => Phase::Streaming(auth),
+                    // Waiting for approval, ask again later
+                    Ok(None) => Phase::Backoff(self.next_delay(), None),
+                    Err(e) => {
+                        println!("Authorization failed: {}", e);
+                        self.after_error(e, None)
+                    }
+                },
+                Phase::Streaming(auth) => {
+                    let started = Instant::now();
+                    let result = self.do_connect(&auth);
+                    if started.elapsed() >= HEALTHY_STREAM {
+                        self.failures = 0;
+                    }
+                    match result {
+                        // Dropped, the session is still good
+                        Ok(()) => Phase::Backoff(self.next_delay(), Some(auth)),
+                        Err(e) if e.is_session_expired() => {
+                            println!("Session expired: {}", e);
+                            Phase::Authorizing
+                        }
+                        Err(e) => {
+                            println!("Connection failed: {}", e);
+                            self.after_error(e, Some(auth))
+                        }
+                    }
+                }
+                Phase::Backoff(delay, resume) => {
+                    println!("Retrying in {} seconds", delay.as_secs_f32().ceil());
+                    sleep(delay, self.exit);
+                    match resume {
+                        Some(auth) => Phase::Streaming(auth),
+                        None => Phase::Authorizing,
+                    }
+                }
+                Phase::Revoked(e) => return Err(e.into()),
+            };
+        }
+        Ok(())
+    }
+
+    /// Records the phase for `sitepi --status`.
+    fn enter(&mut self, phase: &Phase) {
+        if self.status.phase != phase.name() {
+            self.status.phase = phase.name().to_string();
+            self.status.since = status::now();
+            self.save_status();
+        }
+    }
+
+    /// Records that the controller sent something, at most once a second.
+    fn heard(&mut self) {
+        let now = status::now();
+        if self.status.last_message != Some(now) {
+            self.status.last_message = Some(now);
+            self.save_status();
+        }
+    }
+
+    fn save_status(&self) {
+        if let Err(e) = status::save(&self.options.interface, &self.status) {
+            println!("Failed to save the status: {}", e);
+        }
+    }
+
+    /// The phase after a failed request, `resume` is the session to keep
+    /// streaming with.
+    fn after_error(&mut self, error: api::Error, resume: Option<Authorization>) -> Phase {
+        match error {
+            // Retrying cannot fix a refused provision code or key
+            api::Error::ProvisionRejected(_) | api::Error::NoProof => Phase::Revoked(error),
+            _ if error.is_refusal() => Phase::Revoked(error),
+            api::Error::Status {
+                status,
+                retry_after: Some(delay),
+            } if status == StatusCode::TOO_MANY_REQUESTS
+                || status == StatusCode::SERVICE_UNAVAILABLE =>
+            {
+                self.failures += 1;
+                Phase::Backoff(delay.min(self.options.max_backoff), resume)
+            }
+            _ => Phase::Backoff(self.next_delay(), resume),
+        }
+    }
+
+    /// Full jitter: anywhere between zero and the capped exponential delay,
+    /// so clients cut off together do not come back together.
+    fn next_delay(&mut self) -> Duration {
+        let cap = BASE_DELAY
+            .saturating_mul(1 << self.failures.min(16))
+            .min(self.options.max_backoff);
+        self.failures += 1;
+        cap.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+
+    /// Authorizes and applies the overlay addresses, `None` while a
+    /// provision code waits for approval.
+    fn do_authorize(&mut self) -> Result<Option<Authorization>, api::Error> {
+        println!(" ============== Authorize ================ ");
+
+        // The code is sent until the controller bound or rejected it
+        let sent_code = self.provision_code.take();
+        let auth = match api::authorize(
+            &self.options.server,
+            Some(self.pubkey),
+            Some(self.listen_port),
+            sent_code.as_deref(),
+            Offer {
+                transports: match &self.options.transport {
+                    Some(transport) => std::slice::from_ref(transport),
+                    None => &Transport::ALL,
+                },
+                heartbeat: self.options.idle_timeout.map(|idle| idle / 3),
+            },
+            self.secret.as_ref(),
+            &self.options.tls,
+        ) {
+            Ok(auth) => auth,
+            Err(e @ api::Error::ProvisionRejected(_)) => return Err(e),
+            Err(e) => {
+                self.provision_code = sent_code;
+                return Err(e);
+            }
+        };
+
+        println!("  next URL: {}", auth.url.as_deref().unwrap_or_default());
+        println!("next PROXY: {}", auth.proxy.as_deref().unwrap_or_default());
+        println!(
+            "   NETWORK: {}",
+            auth.network.as_deref().unwrap_or_default()
+        );
+        println!("    IPADDR: {}", auth.ipaddr.as_deref().unwrap_or_default());
+        if let Some(prefix) = &auth.prefix {
+            println!("    PREFIX: {}", prefix);
+        }
+        println!("  PROTOCOL: {}", auth.protocol);
+        match auth.heartbeat {
+            Some(interval) => println!(" HEARTBEAT: {}s", interval.as_secs()),
+            None => println!(" HEARTBEAT: none"),
+        }
+
+        match auth.provision {
+            Some(Provision::Pending) => {
+                println!("Provision code accepted, waiting for approval in the dashboard");
+                // The approval only shows as the next answer to the code
+                self.provision_code = sent_code;
+                return Ok(None);
+            }
+            Some(Provision::Bound)
+                if sent_code.is_some()
+                    || provision::load(self.dir, &self.options.interface).is_none() =>
+            {
+                self.bind(&auth, sent_code.as_deref())
+            }
+            // Controllers without provisioning support only answer with a session
+            None if sent_code.is_some() && auth.session.is_some() => {
+                self.bind(&auth, sent_code.as_deref())
+            }
+            _ => {}
+        }
+        if auth.session.is_some() {
+            if auth.session != self.session {
+                self.sequence = None;
+            }
+            self.session.clone_from(&auth.session);
+            self.controller_key = self.controller_key(&auth);
+        }
+
+        let addrs = overlay_addresses(
+            auth.ipaddr.as_deref().unwrap_or_default(),
+            auth.prefix.as_deref(),
+        );
+        if !addrs.is_empty() {
+            self.reconciler.set_addresses(&addrs);
+            // The watch thread tries again
+            if let Err(e) = self.reconciler.reconcile(self.adapter) {
+                println!("Failed to set address: {}", e);
+            }
+        }
+
+        // The controller answered properly
+        self.failures = 0;
+        Ok(Some(auth))
+    }
+
+    /// Records a new enrollment and pins the signing key the controller of
+    /// the new network offers with it.
+    fn bind(&self, auth: &Authorization, code: Option<&str>) {
+        let interface = &self.options.interface;
+        let binding = provision::Binding::new(auth.network.as_deref().unwrap_or("unknown"), code);
+        if let Err(e) = provision::save(self.dir, interface, &binding) {
+            println!("Failed to save the provisioning binding: {}", e);
+            return;
+        }
+        println!("Provisioned into network {}", binding.network);
+        let pinned = match auth.signing_key {
+            Some(offered) if VerifyingKey::from_bytes(&offered).is_ok() => {
+                provision::pin(self.dir, interface, &offered).map(|()| {
+                    println!(
+                        "Pinned the controller signing key {}",
+                        BASE64.encode(offered)
+                    )
+                })
+            }
+            offered => {
+                if offered.is_some() {
+                    println!("Controller offered an invalid signing key, not pinning it");
+                }
+                provision::unpin(self.dir, interface)
+            }
+        };
+        if let Err(e) = pinned {
+            println!("Failed to pin the controller signing key: {}", e);
+        }
+    }
+
+    /// The key the lines of the session must be signed with. A pinned key
+    /// always wins, without one the key offered for the session is used
+    /// but not trusted beyond it.
+    fn controller_key(&self, auth: &Authorization) -> Option<[u8; 32]> {
+        match (
+            provision::pinned_key(self.dir, &self.options.interface),
+            auth.signing_key,
+        ) {
+            (Some(pinned), Some(offered)) if pinned != offered => {
+                println!("Controller offered another signing key, keeping the pinned one");
+                Some(pinned)
+            }
+            (Some(pinned), _) => Some(pinned),
+            (None, Some(offered)) if VerifyingKey::from_bytes(&offered).is_ok() => {
+                println!("No controller signing key is pinned, checking with the offered one");
+                Some(offered)
+            }
+            (None, offered) => {
+                if offered.is_some() {
+                    println!("Controller offered an invalid signing key");
+                }
+                println!("No controller signing key, control messages are not verified");
+                None
+            }
+        }
+    }
+
+    /// Follows the control stream until it ends, goes idle or `exit` is set.
+    fn do_connect(&mut self, auth: &Authorization) -> Result<(), api::Error> {
+        let (Some(session), Some(url)) = (&auth.session, &auth.url) else {
+            return Err(api::Error::NoSession);
+        };
+
+        println!(" ========================================= ");
+
+        // Old controllers only serve lines, but may hand out a ws:// URL
+        let transport =
+            self.options
+                .transport
+                .or(auth.transport)
+                .unwrap_or(if url.starts_with("ws") {
+                    Transport::WebSocket
+                } else {
+                    Transport::Chunked
+                });
+        println!(" TRANSPORT: {}", transport);
+        let mut stream = api::connect(
+            session,
+            url,
+            auth.proxy.as_deref(),
+            self.revision,
+            transport,
+            READ_TICK,
+            &self.options.tls,
+        )?;
+        // Version 2 marks where its listing ends, version 1 goes quiet
+        let initial_sync = auth.protocol < 2;
+        if initial_sync {
+            self.reconciler.begin_initial_sync();
+        }
+
+        // A controller that promised no heartbeats may well stay quiet, give
+        // the one that did a few missed beats of slack
+        let idle = self
+            .options
+            .idle_timeout
+            .zip(auth.heartbeat)
+            .map(|(idle, interval)| idle.max(interval * 3));
+
+        // Continuously read messages from the stream
+        let mut last_message = Instant::now();
+        while !self.exiting() {
+            match stream.read_message() {
+                Ok(Some(message)) => {
+                    last_message = Instant::now();
+                    self.heard();
+                    self.handle_message(auth.protocol, &message);
+                }
+                // The listing may have been cut short, the next stream
+                // continues it
+                Ok(None) => {
+                    println!("Connection closed");
+                    return Ok(());
+                }
+                // Nothing arrived, a partial message stays in the stream
+                Err(e) if api::is_timeout(&e) => {
+                    if initial_sync && self.reconciler.end_initial_sync() {
+                        self.reconcile();
+                    }
+                    // A half-open connection never fails on its own
+                    if let Some(idle) = idle {
+                        if last_message.elapsed() >= idle {
+                            println!("No message for {} seconds, reconnecting", idle.as_secs());
+                            return Ok(());
+                        }
+                    }
+                }
+                Err(e) => {
+                    println!("Read error: {}", e.source().unwrap_or(&e));
+                    return Ok(());
+                }
+            }
+        }
+        println!("Leaving the control stream");
+        Ok(())
+    }
+
+    fn handle_message(&mut self, protocol: u32, line: &str) {
+        let session = self.session.as_deref().unwrap_or_default();
+        let line = match message::verify(
+            line,
+            session,
+            self.controller_key.as_ref(),
+            &mut self.sequence,
+        ) {
+            Ok(message) => message,
+            Err(e) => {
+                println!("Rejecting message ({}): {}", e, line);
+                return;
+            }
+        };
+        let parsed = if protocol >= 2 {
+            ControlMessage::parse_json(line)
+        } else {
+            ControlMessage::parse(line).map(|message| message.map(|message| (message, None)))
+        };
+        let (message, revision) = match parsed {
+            Ok(Some(parsed)) => parsed,
+            Ok(None) => return,
+            Err(e) => {
+                println!("Skipping bad message: {} ({})", line, e);
+                return;
+            }
+        };
+        if let (Some(revision), Some(applied)) = (revision, self.revision) {
+            // A listing replaces everything, even after a controller reset
+            if revision <= applied && message != ControlMessage::ResyncEnd {
+                println!("Skipping revision {}, already at {}", revision, applied);
+                return;
+            }
+        }
+        if revision.is_some() {
+            self.revision = revision;
+        }
+
+        let reconciler = self.reconciler;
+        match message {
+            ControlMessage::Heartbeat => return,
+            // The controller moved us to another overlay address
+            ControlMessage::Peer(peer, _) if peer.public_key == self.pubkey => {
+                readdress(&peer.allowed_ips, reconciler)
+            }
+            ControlMessage::Peer(peer, info) => {
+                if let Some(name) = &info.name {
+                    let tags = if info.tags.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" [{}]", info.tags.join(","))
+                    };
+                    println!("Peer {}: {}{}", name, BASE64.encode(peer.public_key), tags);
+                }
+                reconciler.set_peer(peer)
+            }
+            ControlMessage::RemovePeer(public_key) if public_key == self.pubkey => return,
+            ControlMessage::RemovePeer(public_key) => reconciler.remove_peer(&public_key),
+            ControlMessage::ResyncBegin => reconciler.begin_resync(),
+            ControlMessage::ResyncEnd => reconciler.end_resync(),
+            ControlMessage::Unknown(line) => {
+                println!("Unknown message: {}", line);
+                return;
+            }
+        }
+        self.reconcile();
+    }
+
+    fn reconcile(&self) {
+        if let Err(e) = self.reconciler.reconcile(self.adapter) {
+            println!("Failed to set peers: {}", e);
+        }
+    }
+
+    /// Undoes the configuration as far as [`Options::teardown`] asks and
+    /// tells the controller we are gone.
+    fn shutdown(&self) {
+        let teardown = self.options.teardown;
+        println!("Shutting down, teardown: {}", teardown);
+        if teardown >= Teardown::Routes {
+            self.reconciler.teardown(self.adapter);
+        }
+        if teardown >= Teardown::Peers {
+            self.reconciler.clear_peers(self.adapter);
+        }
+        if teardown >= Teardown::Down {
+            if let Err(e) = self.adapter.down() {
+                println!("Failed to bring the interface down: {}", e);
+            }
+        }
+
+        if let Some(session) = &self.session {
+            match api::offline(
+                &self.options.server,
+                session,
+                self.pubkey,
+                &self.options.tls,
+            ) {
+                Ok(()) => println!("Controller notified, going offline"),
+                Err(e) => println!("Failed to notify the controller: {}", e),
+            }
+        }
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+    }
+}
+
+/// The private key of the interface from its key file. Interfaces set up
+/// elsewhere may have none, or a stale one.
+fn load_secret(interface: &str, public_key: &[u8; 32]) -> Option<StaticSecret> {
+    let (private_key, _) = key::load(interface).ok().flatten()?;
+    let secret = StaticSecret::from(private_key);
+    (PublicKey::from(&secret).as_bytes() == public_key).then_some(secret)
+}
+
+/// Parses the comma-separated `x-ipaddr` list, one address per family.
+/// An entry either carries its prefix as CIDR or takes it from the `x-prefix`
+/// list at the same position. Old controllers send neither and get /24 for
+/// IPv4 and /64 for IPv6.
+fn overlay_addresses(ipaddr: &str, prefix: Option<&str>) -> Vec<IpNet> {
+    let prefixes: Vec<&str> = prefix
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .collect();
+    let mut addrs: Vec<IpNet> = Vec::new();
+    for (i, value) in ipaddr.split(',').map(str::trim).enumerate() {
+        if value.is_empty() {
+            continue;
+        }
+        let addr = match value.parse::<IpNet>() {
+            Ok(net) => net,
+            Err(_) => {
+                let Ok(addr) = value.parse::<IpAddr>() else {
+                    println!("Skipping invalid IPADDR: {}", value);
+                    continue;
+                };
+                let default = if addr.is_ipv4() { 24 } else { 64 };
+                let prefix_len = match prefixes.get(i).filter(|p| !p.is_empty()) {
+                    Some(p) => p
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|len| IpNet::new(addr, *len).is_ok()),
+                    None => Some(default),
+                };
+                let prefix_len = prefix_len.unwrap_or_else(|| {
+                    println!("Ignoring invalid PREFIX for {}, using /{}", addr, default);
+                    default
+                });
+                IpNet::new(addr, prefix_len).unwrap()
+            }
+        };
+        if addrs
+            .iter()
+            .any(|a| a.addr().is_ipv4() == addr.addr().is_ipv4())
+        {
+            continue;
+        }
+        addrs.push(addr);
+    }
+    addrs
+}
+
+fn readdress(allowed_ips: &[IpNet], reconciler: &Reconciler) {
+    let current = reconciler.addresses();
+    let same_family = |a: &IpNet, b: &IpNet| a.addr().is_ipv4() == b.addr().is_ipv4();
+
+    // The first allowed IP of each family is our new address
+    let mut addrs: Vec<IpNet> = Vec::new();
+    for new in allowed_ips {
+        if addrs.iter().any(|a| same_family(a, new)) {
+            continue;
+        }
+        let addr = match current.iter().find(|old| same_family(old, new)) {
+            // A bare address keeps the prefix of the current one
+            Some(old) if new.prefix_len() == new.max_prefix_len() => {
+                IpNet::new(new.addr(), old.prefix_len()).unwrap_or(*new)
+            }
+            _ => *new,
+        };
+        addrs.push(addr);
+    }
+    if addrs.is_empty() {
+        return;
+    }
+    // Families the message does not mention keep their address
+    for old in &current {
+        if !addrs.iter().any(|a| same_family(a, old)) {
+            addrs.push(*old);
+        }
+    }
+
+    reconciler.set_addresses(&addrs);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::MockBackend;
+    use crate::backend::Peer;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    fn net(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn overlay_addresses_from_headers() {
+        let cases: [(&str, Option<&str>, &[&str]); 14] = [
+            ("", None, &[]),
+            (" , ", Some("16"), &[]),
+            // Old controllers send neither CIDR nor x-prefix
+            ("10.9.0.2", None, &["10.9.0.2/24"]),
+            ("fd00::2", None, &["fd00::2/64"]),
+            ("10.9.0.2/16", None, &["10.9.0.2/16"]),
+            // CIDR wins over x-prefix
+            ("10.9.0.2/16", Some("8"), &["10.9.0.2/16"]),
+            (
+                "10.9.0.2, fd00::2",
+                Some("16,48"),
+                &["10.9.0.2/16", "fd00::2/48"],
+            ),
+            // Prefixes go by position, an empty one falls back
+            (
+                "10.9.0.2,fd00::2",
+                Some(",56"),
+                &["10.9.0.2/24", "fd00::2/56"],
+            ),
+            (
+                "10.9.0.2,fd00::2",
+                Some("20"),
+                &["10.9.0.2/20", "fd00::2/64"],
+            ),
+            ("10.9.0.2", Some("33"), &["10.9.0.2/24"]),
+            ("fd00::2", Some("129"), &["fd00::2/64"]),
+            ("10.9.0.2", Some("wide"), &["10.9.0.2/24"]),
+            // One address per family, invalid entries are skipped
+            ("nope,10.9.0.2,10.9.0.3", None, &["10.9.0.2/24"]),
+            ("10.9.0.2/40,fd00::2/64", None, &["fd00::2/64"]),
+        ];
+        for (ipaddr, prefix, expected) in cases {
+            let expected: Vec<IpNet> = expected.iter().map(|addr| net(addr)).collect();
+            assert_eq!(
+                overlay_addresses(ipaddr, prefix),
+                expected,
+                "{:?} {:?}",
+                ipaddr,
+                prefix
+            );
+        }
+    }
+
+    #[test]
+    fn readdress_moves_the_routes() {
+        let dir = std::env::temp_dir().join(format!("sitepi-{}-client", std::process::id()));
+        let reconciler = Reconciler::new(dir.to_str().unwrap(), "wgtest", true);
+        let adapter = MockBackend::new("wgtest");
+        reconciler.set_addresses(&[net("10.9.0.2/24"), net("fd00::2/64")]);
+        let peer = Peer {
+            public_key: [1; 32],
+            preshared_key: None,
+            endpoint: Some("192.0.2.1:51820".parse().unwrap()),
+            allowed_ips: vec![
+                net("10.9.0.5/32"),
+                net("192.168.50.0/24"),
+                net("fd00::5/128"),
+                net("fd00:50::/64"),
+            ],
+            keepalive: 25,
+        };
+        reconciler.set_peer(peer.clone());
+        reconciler.reconcile(&adapter).unwrap();
+        let routes = [
+            (net("192.168.50.0/24"), "10.9.0.5".parse().unwrap()),
+            (net("fd00:50::/64"), "fd00::5".parse().unwrap()),
+        ];
+        assert_eq!(adapter.state().routes, routes);
+
+        // A bare address keeps the prefix, the other family its address
+        readdress(&[net("10.9.0.7/32")], &reconciler);
+        reconciler.reconcile(&adapter).unwrap();
+        assert_eq!(
+            adapter.state().addresses,
+            [net("10.9.0.7/24"), net("fd00::2/64")]
+        );
+        assert!(adapter.state().up);
+        assert_eq!(adapter.state().peers, std::slice::from_ref(&peer));
+        assert_eq!(adapter.state().routes, routes);
+
+        readdress(&[net("fd01::2/128"), net("10.8.0.2/16")], &reconciler);
+        reconciler.reconcile(&adapter).unwrap();
+        assert_eq!(
+            adapter.state().addresses,
+            [net("fd01::2/64"), net("10.8.0.2/16")]
+        );
+        assert_eq!(adapter.state().peers, [peer]);
+        assert_eq!(adapter.state().routes, routes);
+
+        // Nothing to move to
+        readdress(&[], &reconciler);
+        reconciler.reconcile(&adapter).unwrap();
+        assert_eq!(
+            adapter.state().addresses,
+            [net("fd01::2/64"), net("10.8.0.2/16")]
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// A controller answering one authorize request per entry of `answers`
+    /// with those headers, reporting the provision code each one carried.
+    fn controller(answers: &[&str]) -> (String, mpsc::Receiver<Option<String>>) {
+        let answers: Vec<String> = answers.iter().map(|answer| answer.to_string()).collect();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = format!("http://{}", listener.local_addr().unwrap());
+        let (codes, received) = mpsc::channel();
+        std::thread::spawn(move || {
+            for headers in answers {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut code = None;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("provision-code") {
+                            code = Some(value.trim().to_string());
+                        }
+                    }
+                    line.clear();
+                }
+                codes.send(code).unwrap();
+                write!(
+                    &stream,
+                    "HTTP/1.1 200 OK\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+                    headers
+                )
+                .unwrap();
+            }
+        });
+        (server, received)
+    }
+
+    fn options(server: String, provision_code: Option<&str>) -> Options {
+        Options {
+            server,
+            interface: "wgtest".to_string(),
+            provision_code: provision_code.map(String::from),
+            route: false,
+            teardown: Teardown::Routes,
+            max_backoff: Duration::from_secs(1),
+            idle_timeout: None,
+            transport: None,
+            tls: Tls::new(None, None, None, &[]).unwrap(),
+        }
+    }
+
+    #[test]
+    fn provision_code_survives_pending() {
+        let dir = std::env::temp_dir().join(format!("sitepi-{}-provision", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let bound = "x-provision: bound\r\nx-network: office\r\n\
+                     x-session: s1\r\nx-url: http://127.0.0.1:1/stream\r\n";
+        let (server, codes) = controller(&["x-provision: pending\r\n", bound, bound]);
+        let options = options(server, Some("ABCD-1234"));
+        let adapter = MockBackend::new("wgtest");
+        let reconciler = Reconciler::new(dir, "wgtest", false);
+        let exit = AtomicBool::new(false);
+        let start = |provision_code| Client {
+            options: &options,
+            dir,
+            adapter: &adapter,
+            reconciler: &reconciler,
+            exit: &exit,
+            pubkey: [7; 32],
+            secret: None,
+            controller_key: None,
+            listen_port: 51820,
+            provision_code,
+            session: None,
+            sequence: None,
+            failures: 0,
+            status: Status::default(),
+            revision: None,
+        };
+
+        let mut client = start(provision_code(dir, &options));
+        assert!(client.do_authorize().unwrap().is_none());
+        assert_eq!(codes.recv().unwrap().as_deref(), Some("ABCD-1234"));
+        assert!(provision::load(dir, "wgtest").is_none());
+
+        // Approved in the dashboard, the code binds on the next attempt
+        assert!(client.do_authorize().unwrap().is_some());
+        assert_eq!(codes.recv().unwrap().as_deref(), Some("ABCD-1234"));
+        let binding = provision::load(dir, "wgtest").unwrap();
+        assert_eq!(binding.network, "office");
+        assert_eq!(binding.spent("ABCD-1234"), Some(true));
+        let file = format!("{}/wgtest.provision", dir);
+        assert!(!std::fs::read_to_string(&file).unwrap().contains("ABCD"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // A restart with the spent code does not send it again
+        let mut client = start(provision_code(dir, &options));
+        assert!(client.do_authorize().unwrap().is_some());
+        assert_eq!(codes.recv().unwrap(), None);
+        assert_eq!(provision::load(dir, "wgtest"), Some(binding));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn pins_the_controller_key_only_when_binding() {
+        let dir = std::env::temp_dir().join(format!("sitepi-{}-pin", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let signing_key = |seed| {
+            ed25519_dalek::SigningKey::from_bytes(&[seed; 32])
+                .verifying_key()
+                .to_bytes()
+        };
+        let (first, second) = (signing_key(7), signing_key(8));
+        let session = "x-session: s1\r\nx-url: http://127.0.0.1:1/stream\r\n";
+        let offer =
+            |key: &[u8; 32]| format!("{}x-signing-key: {}\r\n", session, BASE64.encode(key));
+        let bound = |key| format!("x-provision: bound\r\nx-network: office\r\n{}", offer(key));
+        let answers = [
+            offer(&first),
+            bound(&first),
+            offer(&second),
+            session.to_string(),
+            bound(&second),
+        ];
+        let answers: Vec<&str> = answers.iter().map(String::as_str).collect();
+        let (server, _codes) = controller(&answers);
+        let options = options(server, None);
+        let adapter = MockBackend::new("wgtest");
+        let reconciler = Reconciler::new(dir, "wgtest", false);
+        let exit = AtomicBool::new(false);
+        let mut client = Client {
+            options: &options,
+            dir,
+            adapter: &adapter,
+            reconciler: &reconciler,
+            exit: &exit,
+            pubkey: [7; 32],
+            secret: None,
+            controller_key: None,
+            listen_port: 51820,
+            provision_code: None,
+            session: None,
+            sequence: None,
+            failures: 0,
+            status: Status::default(),
+            revision: None,
+        };
+
+        // Outside of an enrollment the offered key only covers the session
+        client.do_authorize().unwrap();
+        assert_eq!(client.controller_key, Some(first));
+        assert_eq!(provision::pinned_key(dir, "wgtest"), None);
+
+        client.provision_code = Some("ABCD-1234".to_string());
+        client.do_authorize().unwrap();
+        assert_eq!(provision::pinned_key(dir, "wgtest"), Some(first));
+
+        // Neither another key nor none replaces the pin
+        client.do_authorize().unwrap();
+        assert_eq!(client.controller_key, Some(first));
+        client.do_authorize().unwrap();
+        assert_eq!(client.controller_key, Some(first));
+
+        // A new enrollment rotates it
+        client.provision_code = Some("EFGH-5678".to_string());
+        client.do_authorize().unwrap();
+        assert_eq!(provision::pinned_key(dir, "wgtest"), Some(second));
+        assert_eq!(client.controller_key, Some(second));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,149 @@
+//! Interface key file, `<CONFIG_DIR>/<interface>.conf` in wg-quick format.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rand::Rng;
+use std::io::{self, Write};
+use std::path::Path;
+
+// Where the interface keys are kept, /etc/wireguard is shared with wg-quick
+#[cfg(windows)]
+pub const CONFIG_DIR: &str = "configs";
+#[cfg(not(windows))]
+pub const CONFIG_DIR: &str = "/etc/wireguard";
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn path(interface: &str) -> String {
+    format!("{}/{}.conf", CONFIG_DIR, interface)
+}
+
+/// Replaces the file at `path` with `content`, readable by the owner only.
+/// The content goes to a fresh temporary file created with that mode and is
+/// renamed into place, so the secret is never readable by others and a
+/// crash leaves the old file or the new one.
+pub(crate) fn write_secret(path: &str, content: &str) -> io::Result<()> {
+    let temp = format!("{}.tmp", path);
+    // A leftover could carry a wider mode, create_new below refuses it
+    match std::fs::remove_file(&temp) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let written = options.open(&temp).and_then(|mut file| {
+        file.write_all(content.as_bytes())?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|()| std::fs::rename(&temp, path)) {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Reads the private key and listen port of the interface, `None` when the
+/// file does not exist.
+pub fn load(interface: &str) -> io::Result<Option<([u8; 32], u16)>> {
+    let config_path = path(interface);
+    if !Path::new(&config_path).exists() {
+        return Ok(None);
+    }
+    let mut private_bytes = [0; 32];
+    let mut port: u16 = 0;
+
+    // Read the existing configuration
+    let config_content = std::fs::read_to_string(&config_path)?;
+    // Parse the private key and port from the configuration
+    for line in config_content.lines() {
+        if line.starts_with("PrivateKey") {
+            let parts: Vec<&str> = line.split('=').collect();
+            if parts.len() >= 2 {
+                let private_key = parts[1].trim().to_owned() + "=";
+
+                let decoded_key = BASE64.decode(private_key).map_err(|e| {
+                    invalid(format!("Failed to decode private key from base64: {}", e))
+                })?;
+                if decoded_key.len() != 32 {
+                    return Err(invalid(format!(
+                        "Private key length is incorrect, should be 32 bytes, but got {} bytes",
+                        decoded_key.len()
+                    )));
+                }
+                private_bytes.copy_from_slice(&decoded_key);
+            }
+        } else if line.starts_with("ListenPort") {
+            let parts: Vec<&str> = line.split('=').collect();
+            if parts.len() >= 2 {
+                port = parts[1]
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid(format!("Invalid port number: {}", parts[1].trim())))?;
+            }
+        }
+    }
+
+    Ok(Some((private_bytes, port)))
+}
+
+/// Reads the private key and listen port of the interface, generating and
+/// saving new ones when the file does not exist.
+pub fn load_or_create(interface: &str) -> io::Result<([u8; 32], u16)> {
+    if let Some(key) = load(interface)? {
+        println!(
+            "Reading the existing configuration file: {}.conf",
+            interface
+        );
+        return Ok(key);
+    }
+
+    println!("no {}.conf found, create it", interface);
+    let private = x25519_dalek::StaticSecret::random();
+    let mut private_bytes = [0; 32];
+    private_bytes.copy_from_slice(private.as_bytes());
+    // Generate a random port number between 1024 and 65535
+    let port = rand::thread_rng().gen_range(1024..65535);
+    let new_private_key = BASE64.encode(private_bytes);
+    let new_config = format!(
+        "[Interface]\nPrivateKey = {}\nListenPort = {}\n",
+        new_private_key, port
+    );
+    std::fs::create_dir_all(CONFIG_DIR)?;
+    write_secret(&path(interface), &new_config)?;
+
+    Ok((private_bytes, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_files() {
+        let dir = std::env::temp_dir().join(format!("sitepi-{}-secret", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wgtest.conf");
+        let path = path.to_str().unwrap();
+
+        write_secret(path, "first\n").unwrap();
+        // A leftover temporary file is replaced, not reused
+        std::fs::write(format!("{}.tmp", path), "stale").unwrap();
+        write_secret(path, "second\n").unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "second\n");
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
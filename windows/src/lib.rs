@@ -0,0 +1,17 @@
+//! SitePi SD-WAN client library.
+//!
+//! The `sitepi` binary is a thin wrapper around [`client::run`]. The pieces
+//! are public so provisioning tools can talk to the controller on their own.
+
+pub mod api;
+pub mod backend;
+pub mod client;
+pub mod key;
+pub mod message;
+pub mod peers;
+pub mod provision;
+pub mod routes;
+pub mod state;
+pub mod status;
+pub mod tls;
+pub mod transport;
@@ -1,18 +1,16 @@
-use ipnet::{IpNet, Ipv4Net};
-use rand::Rng;
-use std::error::Error;
-use std::io::BufRead;
-use std::net::Ipv4Addr;
-
-use std::sync::Arc;
-// use ipnet::{Ipv4Net};
 use base64::engine::general_purpose::STANDARD as BASE64;
 use base64::Engine;
 use clap::Parser;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use sitepi::backend::{self, BackendKind};
+use sitepi::client::{self, Options, Teardown};
+use sitepi::tls::Tls;
+use sitepi::transport::Transport;
+use sitepi::{key, status};
 
-use winapi::shared::ipmib::MIB_IPFORWARDROW;
-use winapi::um::iphlpapi::{CreateIpForwardEntry, DeleteIpForwardEntry};
 // Add command line arguments struct
 #[derive(Parser)]
 #[command(name = "sitepi")]
@@ -30,529 +28,146 @@ struct Cli {
     #[arg(short = 'p', long = "provision")]
     provision: Option<String>,
 
-    /// Route auto load
-    #[arg(short = 'r', long = "route")]
+    /// Route auto load, a bare -r as the init scripts pass it means true
+    #[arg(short = 'r', long = "route", num_args = 0..=1, default_missing_value = "true")]
     route: Option<bool>,
-}
 
-static PEERS: Mutex<Vec<wireguard_nt::SetPeer>> = Mutex::new(Vec::new());
+    /// Use userspace WireGuard (boringtun) instead of the kernel module
+    #[arg(short = 'u', long = "userspace")]
+    userspace: bool,
+
+    /// What to remove on shutdown: none, routes, peers or down
+    #[arg(long = "teardown", default_value_t = Teardown::Routes)]
+    teardown: Teardown,
+
+    /// Longest wait between reconnect attempts, in seconds
+    #[arg(long = "max-backoff", default_value_t = 120)]
+    max_backoff: u64,
+
+    /// Reconnect after this many seconds without a message from the
+    /// controller, 0 to wait forever. Only when the controller agrees to
+    /// send heartbeats
+    #[arg(long = "idle-timeout", default_value_t = 90)]
+    idle_timeout: u64,
+
+    /// Read the control stream as chunked, sse or websocket, by default
+    /// the controller picks
+    #[arg(long = "transport")]
+    transport: Option<Transport>,
+
+    /// PEM file with CA certificates to trust for the controller, besides
+    /// the system ones
+    #[arg(long = "ca-file")]
+    ca_file: Option<String>,
+
+    /// Only accept a controller certificate with this key, base64 SHA-256
+    /// of its SubjectPublicKeyInfo. Repeat for backup keys.
+    #[arg(long = "pin")]
+    pin: Vec<String>,
+
+    /// PEM client certificate for mutual TLS with the controller
+    #[arg(long = "client-cert", requires = "client_key")]
+    client_cert: Option<String>,
+
+    /// PKCS#8 PEM key of the client certificate
+    #[arg(long = "client-key", requires = "client_cert")]
+    client_key: Option<String>,
+
+    /// Show the connection status of the running client and exit
+    #[arg(long = "status")]
+    status: bool,
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Parse command line arguments
     let args = Cli::parse();
 
-    let _server = args.server;
     let interface = args.interface;
-    let provision_code = args.provision.clone(); // Use clone() to create a new copy
-    let route = args.route.unwrap_or(false);
 
-    // Use provision code (if provided)
-    if let Some(ref provision_code) = args.provision {
-        // Use ref to borrow the value
-        println!("provision code: {}", provision_code);
-        // TODO: Handle the logic for provision code
+    if args.status {
+        match status::load(&interface) {
+            Some(status) => println!("{}", status),
+            None => println!("Not running on {}", interface),
+        }
+        return Ok(());
     }
 
+    // Bad TLS settings would only show as failed connections later
+    let tls = match Tls::new(
+        args.ca_file.as_deref(),
+        args.client_cert.as_deref(),
+        args.client_key.as_deref(),
+        &args.pin,
+    ) {
+        Ok(tls) => tls,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     // Replace signal handling related code
-    let exit = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let exit = Arc::new(AtomicBool::new(false));
     let exit_clone = Arc::clone(&exit);
 
+    // Ctrl-C, and SIGTERM from systemd or procd. The handler thread must exist
+    // before the adapter, boringtun blocks both signals in the thread that
+    // starts it.
     ctrlc::set_handler(move || {
+        if exit_clone.swap(true, Ordering::Relaxed) {
+            println!("Exiting immediately");
+            std::process::exit(1);
+        }
         println!("Received exit signal, shutting down...");
-        exit_clone.store(true, std::sync::atomic::Ordering::Relaxed);
-        std::process::exit(0);
     })?;
 
     // Must be run as Administrator because we create network adapters
 
-    // Load the wireguard dll file so that we can call the underlying C functions
-    // Unsafe because we are loading an arbitrary dll file
-    let wireguard = unsafe { wireguard_nt::load_from_path("wireguard.dll") }
-        .expect("Failed to load wireguard dll");
-
     // tbd: Check if we have administrator privileges
 
     // Use interface name from command line arguments
-    let adapter = Arc::new(match wireguard_nt::Adapter::open(&wireguard, &interface) {
-        Ok(adapter) => adapter,
-        Err(_) => {
-            println!("{} not found, initialize it", interface);
-            match wireguard_nt::Adapter::create(&wireguard, &interface, "Demo", None) {
-                Ok(adapter) => {
-                    let private = x25519_dalek::StaticSecret::random();
-                    let mut private_bytes = [0; 32];
-                    private_bytes.copy_from_slice(private.as_bytes());
-                    let mut port: u16 = 0;
-
-                    // Check if the configuration file exists for the interface
-                    let config_path = format!("configs/{}.conf", interface);
-                    if std::path::Path::new(&config_path).exists() {
-                        println!(
-                            "Reading the existing configuration file: {}.conf",
-                            interface
-                        );
-                        // Read the existing configuration
-                        let config_content = std::fs::read_to_string(&config_path)
-                            .expect("Failed to read configuration file");
-                        // Parse the private key and port from the configuration
-                        for line in config_content.lines() {
-                            if line.starts_with("PrivateKey") {
-                                let parts: Vec<&str> = line.split('=').collect();
-                                if parts.len() >= 2 {
-                                    let private_key = parts[1].trim().to_owned() + "=";
-
-                                    match BASE64.decode(private_key) {
-                                        Ok(decoded_key) => {
-                                            if decoded_key.len() == 32 {
-                                                private_bytes.copy_from_slice(&decoded_key);
-                                            } else {
-                                                eprintln!("Error: Private key length is incorrect, should be 32 bytes, but got {} bytes", decoded_key.len());
-                                                std::process::exit(1);
-                                            }
-                                        }
-                                        Err(e) => {
-                                            eprintln!("Error: Failed to decode private key from base64: {}", e);
-                                            std::process::exit(1);
-                                        }
-                                    }
-                                }
-                            } else if line.starts_with("ListenPort") {
-                                let parts: Vec<&str> = line.split('=').collect();
-                                if parts.len() >= 2 {
-                                    port = parts[1].trim().parse().expect("Invalid port number");
-                                }
-                            }
-                        }
-                    } else {
-                        println!("no {}.conf found, create it", interface);
-                        // Generate a random port number between 1024 and 65535
-                        port = rand::thread_rng().gen_range(1024..65535);
-                        let new_private_key = BASE64.encode(&private_bytes);
-                        let new_config = format!(
-                            "[Interface]\nPrivateKey = {}\nListenPort = {}\n",
-                            new_private_key, port
-                        );
-                        std::fs::write(&config_path, new_config)
-                            .expect("Failed to write configuration file");
-                    }
-
-                    let interface = wireguard_nt::SetInterface {
-                        listen_port: Some(port),
-                        public_key: None,
-                        private_key: Some(private_bytes),
-                        peers: vec![],
-                    };
-                    adapter.set_config(&interface).unwrap();
-                    adapter
-                }
-                Err(e) => {
-                    eprintln!("Failed to create WireGuard adapter: {}", e);
-                    std::process::exit(1);
-                }
-            }
-        }
-    });
-
-    assert!(adapter.set_logging(wireguard_nt::AdapterLoggingLevel::OnWithPrefix));
-
-    let config = adapter.get_config();
-    println!(" public_key: {}", BASE64.encode(config.public_key));
-    println!("listen_port: {}", config.listen_port);
-
-    let mut attempt = 0; // Initialize attempt counter
-    let max_attempts = 5; // Set maximum attempts
-    let mut base_delay = 1; // Base delay in seconds
-                            // Directly call sync function, no runtime needed
-    loop {
-        while attempt < max_attempts {
-            let one_shot = rand::thread_rng().gen_range(800..1200);
-            std::thread::sleep(std::time::Duration::from_millis(base_delay * one_shot)); // Sleep for base delay
-
-            // If the operation is successful, break the loop
-            let _ = do_authorize(
-                &_server,
-                Some(config.public_key),
-                Some(config.listen_port),
-                provision_code.clone(),
-                route,
-                &adapter,
-            );
-
-            // Increase the base delay for the next attempt
-            base_delay *= 2; // Exponential backoff
-            attempt += 1; // Increment attempt counter
-        }
-
-        attempt = 0;
-        base_delay = 1;
-
-        if exit.load(std::sync::atomic::Ordering::Relaxed) {
-            break;
-        }
-    }
-
-    Ok(())
-}
-
-// Change async function to sync function
-fn do_authorize(
-    server: &str,
-    pubkey: Option<[u8; 32]>,
-    listen_port: Option<u16>,
-    provision_code: Option<String>,
-    route: bool,
-    adapter: &Arc<wireguard_nt::Adapter>,
-) -> Result<(), reqwest::Error> {
-    let client = reqwest::blocking::Client::new();
-    let url = if server.starts_with("http") {
-        format!("{}/authorize", server)
+    let kind = if args.userspace {
+        BackendKind::Userspace
     } else {
-        format!("{}/authorize", server)
+        BackendKind::Native
     };
-
-    println!(" ============== Authorize ================ ");
-
-    let mut request = client.post(url).header("User-Agent", "sitepi");
-
-    // Create a vector to hold headers
-    let mut headers = vec![];
-
-    // Add headers conditionally
-    if let Some(key) = pubkey {
-        headers.push(("PUBKEY", BASE64.encode(key)));
-    }
-    if let Some(port) = listen_port {
-        headers.push(("LISTEN-PORT", port.to_string()));
-    }
-    if let Some(code) = provision_code {
-        headers.push(("PROVISION-CODE", code));
-    }
-
-    // Apply headers to the request
-    for (name, value) in headers {
-        request = request.header(name, value);
-    }
-
-    let response = request.send()?;
-
-    if response.status().is_success() {
-        let x_session = response
-            .headers()
-            .get("x-session")
-            .and_then(|h| h.to_str().ok())
-            .map(String::from);
-        let x_network = response
-            .headers()
-            .get("x-network")
-            .and_then(|h| h.to_str().ok())
-            .map(String::from);
-        let x_ipaddr = response
-            .headers()
-            .get("x-ipaddr")
-            .and_then(|h| h.to_str().ok())
-            .map(String::from);
-        let x_url = response
-            .headers()
-            .get("x-url")
-            .and_then(|h| h.to_str().ok())
-            .map(String::from);
-        let x_proxy = response
-            .headers()
-            .get("x-proxy")
-            .and_then(|h| h.to_str().ok())
-            .map(String::from);
-
-        println!("  next URL: {}", x_url.as_ref().unwrap_or(&String::new()));
-        println!("next PROXY: {}", x_proxy.as_ref().unwrap_or(&String::new()));
-        println!(
-            "   NETWORK: {}",
-            x_network.as_ref().unwrap_or(&String::new())
-        );
-        println!(
-            "    IPADDR: {}",
-            x_ipaddr.as_ref().unwrap_or(&String::new())
-        );
-
-        // Define set_ip as a local closure
-        let set_ip = |ipaddr: Option<String>| {
-            if let Some(ipaddr) = ipaddr {
-                if let Ok(ip_addr) = ipaddr.parse::<Ipv4Addr>() {
-                    // Create the network from the parsed IP
-                    let ipnet = Ipv4Net::new(ip_addr, 24).unwrap();
-
-                    // Safely modify PEERS using Mutex
-                    let peers = PEERS.lock().unwrap();
-
-                    // Clone peers when creating the interface configuration
-                    let interface = wireguard_nt::SetInterface {
-                        listen_port: None,
-                        public_key: None,
-                        private_key: None,
-                        peers: peers.clone(),
-                    };
-
-                    // Directly use ipnet, no additional conversion needed
-                    match adapter.set_default_route(&[ipnet.into()], &interface) {
-                        Ok(()) => {}
-                        Err(err) => panic!("Failed to set address: {}", err),
-                    }
-
-                    assert!(adapter.up().is_ok());
-                }
-            }
-        };
-
-        set_ip(x_ipaddr);
-
-        let mut attempt = 0;
-        let max_attempts = 3;
-        let mut base_delay = 1; // reset delay
-
-        // try to connect to the server
-        while attempt < max_attempts {
-            let one_shot = rand::thread_rng().gen_range(800..1200);
-            std::thread::sleep(std::time::Duration::from_millis(base_delay * one_shot)); // Sleep for base delay
-            do_connect(
-                x_session.clone(),
-                x_url.clone(),
-                x_proxy.clone(),
-                route,
-                adapter,
-            );
-
-            attempt += 1;
-            base_delay *= 2;
+    let (adapter, created) = match backend::open_or_create(&interface, kind) {
+        Ok(opened) => opened,
+        Err(e) => {
+            eprintln!("Failed to create WireGuard adapter: {}", e);
+            std::process::exit(1);
         }
-
-        Ok(())
-    } else {
-        Err(response.error_for_status().unwrap_err())
-    }
-}
-
-fn do_connect(
-    x_session: Option<String>,
-    x_url: Option<String>,
-    x_proxy: Option<String>,
-    route: bool,
-    adapter: &Arc<wireguard_nt::Adapter>,
-) {
-    // Check if x_session and x_url are None
-    if x_session.is_none() || x_url.is_none() {
-        println!("Invalid session or URL");
-        return;
-    }
-
-    println!(" ========================================= ");
-
-    let client = if let Some(proxy) = x_proxy {
-        let proxy = reqwest::Proxy::all(proxy).expect("Invalid proxy URL");
-        reqwest::blocking::Client::builder()
-            .proxy(proxy)
-            .timeout(None)
-            .tcp_keepalive(Some(std::time::Duration::from_secs(24)))
-            .build()
-            .expect("Failed to create client with proxy")
-    } else {
-        reqwest::blocking::Client::builder()
-            .timeout(None)
-            .tcp_keepalive(Some(std::time::Duration::from_secs(24)))
-            .build()
-            .expect("Failed to create client")
     };
 
-    let request = client
-        .get(&x_url.unwrap())
-        .header("User-agent", "sitepi")
-        .header("X-Session", x_session.unwrap());
-
-    // Send request and handle streaming response
-    match request.send() {
-        Ok(response) => {
-            if response.status().is_success() {
-                // Change: declare reader as mutable
-                let mut reader = std::io::BufReader::new(response);
-                let mut line = String::new();
-
-                // Continuously read lines from the stream
-                loop {
-                    line.clear();
-                    match reader.read_line(&mut line) {
-                        Ok(n) if n > 0 => {
-                            // println!("Read a line: {:?}", line);
-                            let message = line.trim_end();
-
-                            handle_message(message, route, adapter);
-                        }
-                        Ok(_) => {
-                            println!("Connection closed");
-                            break;
-                        }
-                        Err(e) => {
-                            println!("Read error: {}", e.source().unwrap().to_string());
-                            break;
-                        }
-                    }
-                }
-            } else {
-                println!("Connection failed: {:?}", response.status());
+    if created {
+        let (private_bytes, port) = match key::load_or_create(&interface) {
+            Ok(key) => key,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
             }
-        }
-        Err(err) => {
-            println!("Request error: {:?}", err);
-        }
-    }
-}
-
-fn handle_message(message: &str, route: bool, adapter: &Arc<wireguard_nt::Adapter>) {
-    let data: Vec<&str> = message.split_whitespace().collect();
-    // println!("Split data: {:?}", data);
-
-    // Extract action and public key from the data
-    let action = data[0];
-    let public_key = data[1];
-
-    // Check if the action is "wg" and the data length is 6
-    if action == "wg" && data.len() == 6 {
-        // Initialize endpoint, IP address, and keepalive
-        let mut endpoint = data[3];
-        let ip_str = data[4]; // IP address
-        let mut persistent_keepalive = data[5];
-
-        if endpoint == "x" || endpoint == "" {
-            endpoint = "0.0.0.0:0";
-            persistent_keepalive = "0";
-        }
-
-        // If the IP is not 'x', create an IpNet
-        // let ip_net = IpNet::new(ip_str.parse().unwrap(), 32).unwrap();
-        let ips = if ip_str != "x" {
-            vec![IpNet::new(ip_str.parse().unwrap(), 32).unwrap()]
-        } else {
-            vec![]
         };
-
-        let peer = wireguard_nt::SetPeer {
-            public_key: Some(BASE64.decode(public_key).unwrap().try_into().unwrap()),
-            preshared_key: None,
-            keep_alive: Some(persistent_keepalive.parse().unwrap()),
-            allowed_ips: ips.clone(),
-            endpoint: endpoint.parse().unwrap(),
-        };
-
-        let ip_str = if ips.len() > 0 {
-            ips[0].addr().to_string()
-        } else {
-            "[no allowed ip]".to_string()
-        };
-
-        println!("  add peer: {} {} {}", public_key, endpoint, ip_str);
-
-        // Safely modify PEERS using Mutex
-        let mut peers = PEERS.lock().unwrap();
-        if !peers.iter().any(|p| p.public_key == peer.public_key) {
-            peers.push(peer);
-        } else {
-            peers.retain(|p| p.public_key != peer.public_key);
-            peers.push(peer);
-        }
-
-        // Clone peers when creating the interface configuration
-        let interface = wireguard_nt::SetInterface {
-            listen_port: None,
-            public_key: None,
-            private_key: None,
-            peers: peers.clone(),
-        };
-
-        // Set the config our adapter will use
-        // This lets it know about the peers and keys
-        adapter.set_config(&interface).unwrap();
-
-        if route && ips.len() > 1 {
-            // The first in ips is the peer IP
-            let peer_ip = ips[0];
-
-            // Extract the IPv4 address from peer_ip IpNet
-            if let IpNet::V4(peer_net) = peer_ip {
-                let peer_addr = peer_net.addr(); // Convert to Ipv4Addr
-
-                // Iterate over allowed_ips and add routes
-                for dest in &ips {
-                    if dest != &peer_ip {
-                        match dest {
-                            IpNet::V4(dest_net) => {
-                                println!(" add route for IP: {} via {}", dest_net, peer_addr);
-                                match add_windows_route(*dest_net, peer_addr) {
-                                    Ok(()) => {
-                                        println!("Successfully added route for IP: {}", dest_net)
-                                    }
-                                    Err(error_code) => println!(
-                                        "Failed to add route for IP: {} with error code: {}",
-                                        dest_net, error_code
-                                    ),
-                                }
-                            }
-                            IpNet::V6(_) => continue, // Skip IPv6
-                        }
-                    }
-                }
-            }
-        }
+        adapter.set_key(&private_bytes, port)?;
     }
-}
 
-// Add new function
-fn add_windows_route(dest_net: Ipv4Net, next_hop: Ipv4Addr) -> Result<(), i32> {
-    let mut route_entry = MIB_IPFORWARDROW {
-        dwForwardDest: u32::from(dest_net.network()),
-        dwForwardMask: u32::from(dest_net.netmask()),
-        dwForwardNextHop: u32::from(next_hop),
-        dwForwardIfIndex: 0,
-        dwForwardMetric1: 0,
-        dwForwardMetric2: 0,
-        dwForwardMetric3: 0,
-        dwForwardMetric4: 0,
-        dwForwardMetric5: 0,
-        dwForwardAge: 0,
-        dwForwardNextHopAS: 0,
-        dwForwardPolicy: 0, // Default policy
-        ForwardType: 4,     // 4 represents a remote route
-        ForwardProto: 3,    // 3 represents a static route
-    };
-
-    // Add the route
-    let result = unsafe { CreateIpForwardEntry(&mut route_entry) };
-    if result != 0 {
-        Err(result as i32)
-    } else {
-        Ok(())
-    }
-}
+    let config = adapter.stats()?;
+    println!(" public_key: {}", BASE64.encode(config.public_key));
+    println!("listen_port: {}", config.listen_port);
 
-fn del_windows_route(dest_net: Ipv4Net, next_hop: Ipv4Addr) -> Result<(), i32> {
-    let mut route_entry = MIB_IPFORWARDROW {
-        dwForwardDest: u32::from(dest_net.network()),
-        dwForwardMask: u32::from(dest_net.netmask()),
-        dwForwardNextHop: u32::from(next_hop),
-        dwForwardIfIndex: 0,
-        dwForwardMetric1: 0,
-        dwForwardMetric2: 0,
-        dwForwardMetric3: 0,
-        dwForwardMetric4: 0,
-        dwForwardMetric5: 0,
-        dwForwardAge: 0,
-        dwForwardNextHopAS: 0,
-        dwForwardPolicy: 0, // Default policy
-        ForwardType: 4,     // 4 represents a remote route
-        ForwardProto: 3,    // 3 represents a static route
+    let options = Options {
+        server: args.server,
+        interface: interface.clone(),
+        provision_code: args.provision,
+        route: args.route.unwrap_or(false),
+        teardown: args.teardown,
+        max_backoff: Duration::from_secs(args.max_backoff),
+        transport: args.transport,
+        idle_timeout: (args.idle_timeout > 0).then(|| Duration::from_secs(args.idle_timeout)),
+        tls,
     };
-
-    // Delete the route
-    let result = unsafe { DeleteIpForwardEntry(&mut route_entry) };
-    if result != 0 {
-        Err(result as i32)
-    } else {
-        Ok(())
+    if let Err(e) = client::run(&options, adapter.as_ref(), &exit) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
     }
+    Ok(())
 }
@@ -0,0 +1,829 @@
+//! Control-stream messages.
+//!
+//! The controller sends one message per line in the format agreed on at
+//! authorize, see [`crate::api::PROTOCOL_VERSION`].
+//!
+//! # Version 1
+//!
+//! A verb followed by space-separated fields, `x` stands for an empty field.
+//!
+//! - `wg <pubkey> <psk> <endpoint> <allowed_ips> <keepalive>` adds or
+//!   replaces a peer. Keys are base64, the preshared key is optional.
+//!   `allowed_ips` is a comma-separated list, the peer's own overlay address
+//!   first and then the subnets behind it.
+//! - `wg <pubkey> x x x x` removes the peer.
+//! - `resync begin` ... `resync end` wraps a full listing of the peers, the
+//!   ones not listed in between are removed.
+//! - `ping` is a heartbeat, it only tells the client the stream is alive.
+//!
+//! # Version 2
+//!
+//! One JSON object per line, told apart by `type`. Unknown fields are
+//! ignored and unknown types skipped, so the controller can extend them.
+//!
+//! - `{"type":"peer","public_key":..,"preshared_key":..,"name":..,
+//!   "endpoints":[..],"addresses":[..],"routes":[..],"keepalive":25,
+//!   "tags":[..]}` adds or replaces a peer. Only `public_key` is required.
+//!   `addresses` are the peer's overlay addresses, `routes` the subnets
+//!   behind it, routed via the address of the same family. WireGuard takes
+//!   the first of `endpoints`.
+//! - `{"type":"remove","public_key":..}` removes the peer.
+//! - `{"type":"resync","phase":"begin"}` ... `{"type":"resync","phase":"end"}`
+//!   wraps a full listing as in version 1.
+//! - `{"type":"ping"}` is a heartbeat.
+//!
+//! Peer and remove messages carry the revision of the network state they
+//! bring the client to in `rev`, increasing with every change. A stream
+//! starts with a full listing whose `resync` end marker carries its `rev`,
+//! or, when the client sent the revision it has, with only the changes
+//! since. Messages inside a listing carry no `rev`.
+//!
+//! # Signatures
+//!
+//! In either version a line may be prefixed with `sig=<seq>:<base64> `, an
+//! ed25519 signature over `sitepi stream <session> <seq>\n<message>`. The
+//! session in it keeps lines from being replayed into another stream, the
+//! sequence number, counting up over all streams of a session, from being
+//! replayed within one. Once the controller's key is pinned, see
+//! [`crate::provision`], lines without a valid signature are rejected.
+//! Before that the key the controller offered at authorize, if any, checks
+//! the lines of its session.
+
+use crate::backend::Peer;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ed25519_dalek::{Signature, VerifyingKey};
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+/// A parsed control-stream line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlMessage {
+    /// Add or replace a peer.
+    Peer(Peer, PeerInfo),
+    /// Remove the peer with this public key.
+    RemovePeer([u8; 32]),
+    /// A full peer listing follows.
+    ResyncBegin,
+    /// The full peer listing is complete.
+    ResyncEnd,
+    /// Heartbeat, nothing to apply.
+    Heartbeat,
+    /// A verb this client does not know, with the whole line.
+    Unknown(String),
+}
+
+/// What version 2 tells about a peer beyond its WireGuard settings, empty
+/// for version 1.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+    /// Endpoints after the one in use
+    pub alternate_endpoints: Vec<SocketAddr>,
+}
+
+/// Why a signed line was rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SignatureError {
+    /// A key is pinned but the line carries no signature.
+    Missing,
+    /// The signature does not decode or does not match.
+    Invalid,
+    /// The sequence number is not above the last one of the session.
+    Replayed,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Missing => write!(f, "unsigned"),
+            SignatureError::Invalid => write!(f, "bad signature"),
+            SignatureError::Replayed => write!(f, "replayed"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Splits off the signature of a line and checks it against `key`, giving
+/// the message. `sequence` is the last sequence number accepted in the
+/// session and moves up to the one of the line. Without a key the
+/// signature is dropped unchecked.
+pub fn verify<'a>(
+    line: &'a str,
+    session: &str,
+    key: Option<&[u8; 32]>,
+    sequence: &mut Option<u64>,
+) -> Result<&'a str, SignatureError> {
+    let (signature, message) = match line
+        .strip_prefix("sig=")
+        .and_then(|rest| rest.split_once(' '))
+    {
+        Some((signature, message)) => (Some(signature), message),
+        None => (None, line),
+    };
+    let Some(key) = key else {
+        return Ok(message);
+    };
+    // Keep-alives of the transport
+    if message.trim().is_empty() {
+        return Ok(message);
+    }
+    let signature = signature.ok_or(SignatureError::Missing)?;
+    // A broken pinned key rejects everything
+    let key = VerifyingKey::from_bytes(key).map_err(|_| SignatureError::Invalid)?;
+    let (seq, signature) = signature
+        .split_once(':')
+        .and_then(|(seq, signature)| Some((seq.parse::<u64>().ok()?, signature)))
+        .ok_or(SignatureError::Invalid)?;
+    let signature = BASE64
+        .decode(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or(SignatureError::Invalid)?;
+    let signed = format!("sitepi stream {} {}\n{}", session, seq, message);
+    key.verify_strict(signed.as_bytes(), &signature)
+        .map_err(|_| SignatureError::Invalid)?;
+    if sequence.is_some_and(|last| seq <= last) {
+        return Err(SignatureError::Replayed);
+    }
+    *sequence = Some(seq);
+    Ok(message)
+}
+
+/// Why a control-stream line was rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// The verb is known but the line has the wrong number of fields.
+    FieldCount {
+        verb: &'static str,
+        expected: usize,
+        found: usize,
+    },
+    /// A field could not be decoded.
+    InvalidField {
+        field: &'static str,
+        value: String,
+        reason: String,
+    },
+    /// A version 2 line is not a JSON object of the documented shape.
+    Json(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::FieldCount {
+                verb,
+                expected,
+                found,
+            } => write!(f, "{} expects {} fields, got {}", verb, expected, found),
+            ParseError::InvalidField {
+                field,
+                value,
+                reason,
+            } => write!(f, "invalid {} '{}': {}", field, value, reason),
+            ParseError::Json(reason) => write!(f, "invalid JSON: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn invalid(field: &'static str, value: &str, reason: impl fmt::Display) -> ParseError {
+    ParseError::InvalidField {
+        field,
+        value: value.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+/// `None` for the `x` placeholder and empty fields.
+fn optional(value: &str) -> Option<&str> {
+    (value != "x" && !value.is_empty()).then_some(value)
+}
+
+fn parse_key(field: &'static str, value: &str) -> Result<[u8; 32], ParseError> {
+    let bytes = BASE64.decode(value).map_err(|e| invalid(field, value, e))?;
+    bytes.try_into().map_err(|bytes: Vec<u8>| {
+        invalid(field, value, format!("{} bytes, expected 32", bytes.len()))
+    })
+}
+
+fn parse_endpoint(value: &str) -> Result<SocketAddr, ParseError> {
+    let endpoint: SocketAddr = value.parse().map_err(|e| invalid("endpoint", value, e))?;
+    // The controller reports IPv4 peers seen on a dual-stack socket as [::ffff:a.b.c.d]
+    Ok(SocketAddr::new(
+        endpoint.ip().to_canonical(),
+        endpoint.port(),
+    ))
+}
+
+fn parse_allowed_ip(value: &str) -> Result<IpNet, ParseError> {
+    // A bare address is a host route
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|e| invalid("allowed IP", value, e))
+}
+
+fn parse_allowed_ips(value: &str) -> Result<Vec<IpNet>, ParseError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty())
+        .map(parse_allowed_ip)
+        .collect()
+}
+
+fn parse_peer(fields: &[&str]) -> Result<ControlMessage, ParseError> {
+    let [_, public_key, preshared_key, endpoint, allowed_ips, keepalive] = fields else {
+        return Err(ParseError::FieldCount {
+            verb: "wg",
+            expected: 6,
+            found: fields.len(),
+        });
+    };
+
+    let public_key = parse_key("public key", public_key)?;
+    if fields[2..].iter().all(|field| optional(field).is_none()) {
+        return Ok(ControlMessage::RemovePeer(public_key));
+    }
+    let preshared_key = optional(preshared_key)
+        .map(|value| parse_key("preshared key", value))
+        .transpose()?;
+    let endpoint = optional(endpoint).map(parse_endpoint).transpose()?;
+    let allowed_ips = optional(allowed_ips)
+        .map(parse_allowed_ips)
+        .transpose()?
+        .unwrap_or_default();
+    // Keepalive only makes sense towards a known endpoint
+    let keepalive = match optional(keepalive) {
+        Some(value) if endpoint.is_some() => {
+            value.parse().map_err(|e| invalid("keepalive", value, e))?
+        }
+        _ => 0,
+    };
+
+    Ok(ControlMessage::Peer(
+        Peer {
+            public_key,
+            preshared_key,
+            endpoint,
+            allowed_ips,
+            keepalive,
+        },
+        PeerInfo::default(),
+    ))
+}
+
+/// A version 2 line with its revision.
+#[derive(Deserialize)]
+struct JsonLine {
+    rev: Option<u64>,
+    #[serde(flatten)]
+    message: JsonMessage,
+}
+
+/// A version 2 message, before its fields are checked.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum JsonMessage {
+    Peer(JsonPeer),
+    Remove {
+        public_key: String,
+    },
+    Resync {
+        phase: String,
+    },
+    Ping,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Deserialize)]
+struct JsonPeer {
+    public_key: String,
+    preshared_key: Option<String>,
+    name: Option<String>,
+    #[serde(default)]
+    endpoints: Vec<String>,
+    #[serde(default)]
+    addresses: Vec<String>,
+    #[serde(default)]
+    routes: Vec<String>,
+    #[serde(default)]
+    keepalive: u16,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+fn json_peer(json: JsonPeer) -> Result<ControlMessage, ParseError> {
+    let public_key = parse_key("public key", &json.public_key)?;
+    let preshared_key = json
+        .preshared_key
+        .as_deref()
+        .map(|value| parse_key("preshared key", value))
+        .transpose()?;
+    let mut endpoints = json
+        .endpoints
+        .iter()
+        .map(|value| parse_endpoint(value))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter();
+    let endpoint = endpoints.next();
+
+    // The addresses go first, the routes are sent via them
+    let mut allowed_ips = json
+        .addresses
+        .iter()
+        .map(|value| parse_allowed_ip(value))
+        .collect::<Result<Vec<_>, _>>()?;
+    for value in &json.routes {
+        let route = parse_allowed_ip(value)?;
+        if !allowed_ips
+            .iter()
+            .any(|ip| ip.addr().is_ipv4() == route.addr().is_ipv4())
+        {
+            return Err(invalid("route", value, "no address of its family"));
+        }
+        allowed_ips.push(route);
+    }
+
+    Ok(ControlMessage::Peer(
+        Peer {
+            public_key,
+            preshared_key,
+            endpoint,
+            allowed_ips,
+            keepalive: if endpoint.is_some() {
+                json.keepalive
+            } else {
+                0
+            },
+        },
+        PeerInfo {
+            name: json.name,
+            tags: json.tags,
+            alternate_endpoints: endpoints.collect(),
+        },
+    ))
+}
+
+impl ControlMessage {
+    /// Parses one line of the control stream, `None` for a blank line.
+    pub fn parse(line: &str) -> Result<Option<ControlMessage>, ParseError> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let Some(verb) = fields.first() else {
+            return Ok(None);
+        };
+        let message = match *verb {
+            "wg" => parse_peer(&fields)?,
+            "resync" => match fields.get(1).copied() {
+                Some("begin") => ControlMessage::ResyncBegin,
+                Some("end") => ControlMessage::ResyncEnd,
+                value => {
+                    return Err(invalid(
+                        "resync marker",
+                        value.unwrap_or_default(),
+                        "expected begin or end",
+                    ))
+                }
+            },
+            "ping" => ControlMessage::Heartbeat,
+            _ => ControlMessage::Unknown(line.trim().to_string()),
+        };
+        Ok(Some(message))
+    }
+
+    /// Parses one version 2 line with its revision, `None` for a blank line.
+    pub fn parse_json(line: &str) -> Result<Option<(ControlMessage, Option<u64>)>, ParseError> {
+        if line.trim().is_empty() {
+            return Ok(None);
+        }
+        let json: JsonLine =
+            serde_json::from_str(line).map_err(|e| ParseError::Json(e.to_string()))?;
+        let message = match json.message {
+            JsonMessage::Peer(peer) => json_peer(peer)?,
+            JsonMessage::Remove { public_key } => {
+                ControlMessage::RemovePeer(parse_key("public key", &public_key)?)
+            }
+            JsonMessage::Resync { phase } => match phase.as_str() {
+                "begin" => ControlMessage::ResyncBegin,
+                "end" => ControlMessage::ResyncEnd,
+                _ => return Err(invalid("resync phase", &phase, "expected begin or end")),
+            },
+            JsonMessage::Ping => ControlMessage::Heartbeat,
+            JsonMessage::Unknown => ControlMessage::Unknown(line.trim().to_string()),
+        };
+        Ok(Some((message, json.rev)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+    const PSK: &str = "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=";
+
+    fn net(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    fn peer(
+        preshared_key: Option<[u8; 32]>,
+        endpoint: Option<&str>,
+        allowed_ips: &[&str],
+        keepalive: u16,
+    ) -> Option<ControlMessage> {
+        Some(ControlMessage::Peer(
+            Peer {
+                public_key: [1; 32],
+                preshared_key,
+                endpoint: endpoint.map(|e| e.parse().unwrap()),
+                allowed_ips: allowed_ips.iter().map(|ip| net(ip)).collect(),
+                keepalive,
+            },
+            PeerInfo::default(),
+        ))
+    }
+
+    /// A version 2 peer without endpoint, the keepalive dropped.
+    fn peer_message(allowed_ips: &[&str]) -> ControlMessage {
+        peer(None, None, allowed_ips, 0).unwrap()
+    }
+
+    /// The field an invalid line was rejected for.
+    fn rejected_field<T>(result: Result<T, ParseError>) -> Option<&'static str> {
+        match result {
+            Err(ParseError::InvalidField { field, .. }) => Some(field),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn parse_version_1() {
+        let cases = [
+            ("", None),
+            ("   \t", None),
+            (
+                &format!("wg {} x 1.2.3.4:51820 10.9.0.5,192.168.50.0/24 25", KEY),
+                peer(
+                    None,
+                    Some("1.2.3.4:51820"),
+                    &["10.9.0.5/32", "192.168.50.0/24"],
+                    25,
+                ),
+            ),
+            (
+                &format!("  wg {} {} [::ffff:1.2.3.4]:51820 10.9.0.5 x  ", KEY, PSK),
+                peer(Some([2; 32]), Some("1.2.3.4:51820"), &["10.9.0.5/32"], 0),
+            ),
+            // Without an endpoint the keepalive is dropped, even a bad one
+            (
+                &format!("wg {} x x 10.9.0.5,,fd00::5 nonsense", KEY),
+                peer(None, None, &["10.9.0.5/32", "fd00::5/128"], 0),
+            ),
+            (
+                &format!("wg {} x [fd00::1]:51820 x 0", KEY),
+                peer(None, Some("[fd00::1]:51820"), &[], 0),
+            ),
+            (
+                &format!("wg {} x x x x", KEY),
+                Some(ControlMessage::RemovePeer([1; 32])),
+            ),
+            ("resync begin", Some(ControlMessage::ResyncBegin)),
+            ("resync end extra", Some(ControlMessage::ResyncEnd)),
+            ("ping", Some(ControlMessage::Heartbeat)),
+            (
+                " route 10.0.0.0/8 ",
+                Some(ControlMessage::Unknown("route 10.0.0.0/8".to_string())),
+            ),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(ControlMessage::parse(line), Ok(expected), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn field_counts() {
+        for (line, found) in [
+            ("wg".to_string(), 1),
+            (format!("wg {} x x x", KEY), 5),
+            (format!("wg {} x x x x x", KEY), 7),
+        ] {
+            assert_eq!(
+                ControlMessage::parse(&line),
+                Err(ParseError::FieldCount {
+                    verb: "wg",
+                    expected: 6,
+                    found
+                }),
+                "{:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_fields() {
+        let cases = [
+            ("wg notbase64! x x x x".to_string(), "public key"),
+            ("wg AQID x 1.2.3.4:1 10.9.0.5 25".to_string(), "public key"),
+            (format!("wg {} AQID x 10.9.0.5 0", KEY), "preshared key"),
+            (format!("wg {} x 1.2.3.4 10.9.0.5 25", KEY), "endpoint"),
+            (format!("wg {} x host:51820 10.9.0.5 25", KEY), "endpoint"),
+            (
+                format!("wg {} x 1.2.3.4:70000 10.9.0.5 25", KEY),
+                "endpoint",
+            ),
+            (format!("wg {} x x 10.9.0.0/33 0", KEY), "allowed IP"),
+            (format!("wg {} x x 10.9.0.5,nope 0", KEY), "allowed IP"),
+            (format!("wg {} x 1.2.3.4:1 10.9.0.5 -1", KEY), "keepalive"),
+            (
+                format!("wg {} x 1.2.3.4:1 10.9.0.5 65536", KEY),
+                "keepalive",
+            ),
+            ("resync".to_string(), "resync marker"),
+            ("resync middle".to_string(), "resync marker"),
+        ];
+        for (line, field) in cases {
+            assert_eq!(
+                rejected_field(ControlMessage::parse(&line)),
+                Some(field),
+                "{:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn parse_version_2() {
+        let full = format!(
+            r#"{{"type":"peer","rev":7,"public_key":"{}","preshared_key":"{}","name":"hq","tags":["dc"],"endpoints":["[::ffff:1.2.3.4]:51820","5.6.7.8:51820"],"addresses":["10.9.0.5","fd00::5/128"],"routes":["192.168.50.0/24","fd00:50::/64"],"keepalive":25,"future":{{"x":1}}}}"#,
+            KEY, PSK
+        );
+        let Ok(Some((ControlMessage::Peer(peer, info), rev))) = ControlMessage::parse_json(&full)
+        else {
+            panic!("{:?}", ControlMessage::parse_json(&full));
+        };
+        assert_eq!(rev, Some(7));
+        assert_eq!(peer.preshared_key, Some([2; 32]));
+        assert_eq!(peer.endpoint, Some("1.2.3.4:51820".parse().unwrap()));
+        assert_eq!(
+            peer.allowed_ips,
+            [
+                "10.9.0.5/32",
+                "fd00::5/128",
+                "192.168.50.0/24",
+                "fd00:50::/64"
+            ]
+            .map(net)
+        );
+        assert_eq!(peer.keepalive, 25);
+        assert_eq!(
+            info,
+            PeerInfo {
+                name: Some("hq".to_string()),
+                tags: vec!["dc".to_string()],
+                alternate_endpoints: vec!["5.6.7.8:51820".parse().unwrap()],
+            }
+        );
+
+        let cases = [
+            ("".to_string(), None),
+            (" ".to_string(), None),
+            (
+                format!(r#"{{"type":"peer","public_key":"{}","keepalive":25}}"#, KEY),
+                Some((peer_message(&[]), None)),
+            ),
+            (
+                format!(r#"{{"type":"remove","public_key":"{}","rev":8}}"#, KEY),
+                Some((ControlMessage::RemovePeer([1; 32]), Some(8))),
+            ),
+            (
+                r#"{"type":"resync","phase":"begin"}"#.to_string(),
+                Some((ControlMessage::ResyncBegin, None)),
+            ),
+            (
+                r#"{"type":"resync","phase":"end","rev":9}"#.to_string(),
+                Some((ControlMessage::ResyncEnd, Some(9))),
+            ),
+            (
+                r#"{"type":"ping"}"#.to_string(),
+                Some((ControlMessage::Heartbeat, None)),
+            ),
+            (
+                r#" {"type":"mystery","x":1} "#.to_string(),
+                Some((
+                    ControlMessage::Unknown(r#"{"type":"mystery","x":1}"#.to_string()),
+                    None,
+                )),
+            ),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(
+                ControlMessage::parse_json(&line),
+                Ok(expected),
+                "{:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_version_2() {
+        for line in [
+            "not json",
+            "[1,2]",
+            r#"{"public_key":"x"}"#,
+            r#"{"type":"peer"}"#,
+            r#"{"type":"remove"}"#,
+            r#"{"type":"peer","public_key":"x","keepalive":-1}"#,
+            r#"{"type":"peer","public_key":"x","endpoints":"1.2.3.4:1"}"#,
+            r#"{"type":"ping","rev":"7"}"#,
+            r#"{"type":"ping"} trailing"#,
+        ] {
+            assert!(
+                matches!(ControlMessage::parse_json(line), Err(ParseError::Json(_))),
+                "{:?}",
+                line
+            );
+        }
+
+        let cases = [
+            (
+                r#"{"type":"remove","public_key":"AQID"}"#.to_string(),
+                "public key",
+            ),
+            (
+                format!(
+                    r#"{{"type":"peer","public_key":"{}","preshared_key":"!"}}"#,
+                    KEY
+                ),
+                "preshared key",
+            ),
+            (
+                format!(
+                    r#"{{"type":"peer","public_key":"{}","endpoints":["1.2.3.4"]}}"#,
+                    KEY
+                ),
+                "endpoint",
+            ),
+            (
+                format!(
+                    r#"{{"type":"peer","public_key":"{}","addresses":["10.9.0.5/40"]}}"#,
+                    KEY
+                ),
+                "allowed IP",
+            ),
+            // Routes need an address of their family to go via
+            (
+                format!(
+                    r#"{{"type":"peer","public_key":"{}","addresses":["10.9.0.5"],"routes":["fd00:50::/64"]}}"#,
+                    KEY
+                ),
+                "route",
+            ),
+            (
+                format!(
+                    r#"{{"type":"peer","public_key":"{}","routes":["192.168.50.0/24"]}}"#,
+                    KEY
+                ),
+                "route",
+            ),
+            (
+                r#"{"type":"resync","phase":"middle"}"#.to_string(),
+                "resync phase",
+            ),
+        ];
+        for (line, field) in cases {
+            assert_eq!(
+                rejected_field(ControlMessage::parse_json(&line)),
+                Some(field),
+                "{:?}",
+                line
+            );
+        }
+    }
+
+    fn sign(session: &str, seq: u64, message: &str) -> String {
+        use ed25519_dalek::{Signer, SigningKey};
+        let signed = format!("sitepi stream {} {}\n{}", session, seq, message);
+        let signature = SigningKey::from_bytes(&[7; 32]).sign(signed.as_bytes());
+        format!(
+            "sig={}:{} {}",
+            seq,
+            BASE64.encode(signature.to_bytes()),
+            message
+        )
+    }
+
+    #[test]
+    fn verify_signatures() {
+        let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32])
+            .verifying_key()
+            .to_bytes();
+        let other = ed25519_dalek::SigningKey::from_bytes(&[8; 32])
+            .verifying_key()
+            .to_bytes();
+        let signed = sign("s1", 5, "ping");
+        // A fresh session for every line
+        fn check<'a>(
+            line: &'a str,
+            session: &str,
+            key: Option<&[u8; 32]>,
+        ) -> Result<&'a str, SignatureError> {
+            verify(line, session, key, &mut None)
+        }
+
+        assert_eq!(check(&signed, "s1", Some(&key)), Ok("ping"));
+        // Without a key the signature is only split off
+        assert_eq!(check(&signed, "s1", None), Ok("ping"));
+        assert_eq!(check("ping", "s1", None), Ok("ping"));
+        // Keep-alives carry no signature
+        assert_eq!(check("", "s1", Some(&key)), Ok(""));
+
+        assert_eq!(
+            check("ping", "s1", Some(&key)),
+            Err(SignatureError::Missing)
+        );
+        // Replayed into another session
+        assert_eq!(
+            check(&signed, "s2", Some(&key)),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(
+            check(&signed, "s1", Some(&other)),
+            Err(SignatureError::Invalid)
+        );
+        let tampered = signed.replace("ping", "resync end");
+        assert_eq!(
+            check(&tampered, "s1", Some(&key)),
+            Err(SignatureError::Invalid)
+        );
+        // Another sequence number than the signed one
+        let renumbered = signed.replacen("sig=5:", "sig=6:", 1);
+        assert_eq!(
+            check(&renumbered, "s1", Some(&key)),
+            Err(SignatureError::Invalid)
+        );
+        for bad in [
+            "sig=!!! ping",
+            "sig=AQID ping",
+            "sig=1:AQID ping",
+            "sig=x:AQID ping",
+        ] {
+            assert_eq!(
+                check(bad, "s1", Some(&key)),
+                Err(SignatureError::Invalid),
+                "{:?}",
+                bad
+            );
+        }
+        // Not a point on the curve
+        let broken = (0..=u8::MAX)
+            .map(|byte| [byte; 32])
+            .find(|key| VerifyingKey::from_bytes(key).is_err())
+            .unwrap();
+        assert_eq!(
+            check(&signed, "s1", Some(&broken)),
+            Err(SignatureError::Invalid)
+        );
+    }
+
+    #[test]
+    fn verify_rejects_replays() {
+        let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32])
+            .verifying_key()
+            .to_bytes();
+        let mut sequence = None;
+        let first = sign("s1", 1, "ping");
+        assert_eq!(verify(&first, "s1", Some(&key), &mut sequence), Ok("ping"));
+        assert_eq!(sequence, Some(1));
+        assert_eq!(
+            verify(&first, "s1", Some(&key), &mut sequence),
+            Err(SignatureError::Replayed)
+        );
+        // Gaps are fine, going back is not
+        let later = sign("s1", 4, "resync end");
+        assert_eq!(
+            verify(&later, "s1", Some(&key), &mut sequence),
+            Ok("resync end")
+        );
+        assert_eq!(
+            verify(&sign("s1", 3, "ping"), "s1", Some(&key), &mut sequence),
+            Err(SignatureError::Replayed)
+        );
+        // A rejected line does not move the sequence
+        assert_eq!(
+            verify(&sign("s2", 9, "ping"), "s1", Some(&key), &mut sequence),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(sequence, Some(4));
+    }
+}
@@ -0,0 +1,723 @@
+//! Desired network state, reconciled with the tunnel backend.
+//!
+//! Control messages only change what the controller wants. [`Reconciler::reconcile`]
+//! compares that with what the backend reports and applies the difference,
+//! so it also repairs an interface that was reset behind our back. What was
+//! applied is saved to the state file and restored on the next start.
+
+use crate::backend::{Backend, Peer, Stats};
+use crate::routes;
+use crate::state::{self, Snapshot};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ipnet::IpNet;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+/// Keeps the interface addresses, peers and routes converged on the state
+/// the controller asked for.
+pub struct Reconciler {
+    dir: String,
+    interface: String,
+    route: bool,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // Nothing is applied before the controller told us what it wants
+    started: bool,
+    addresses: Vec<IpNet>,
+    peers: Vec<Peer>,
+    // Keys listed since the last resync marker, while a resync is running
+    listed: Option<Vec<[u8; 32]>>,
+    // The peers came from the state file, the controller has not confirmed
+    // them yet
+    restored: bool,
+    // What the backend was last told
+    applied_addresses: Vec<IpNet>,
+    applied_peers: Vec<Peer>,
+    routes: Vec<(IpNet, IpAddr)>,
+}
+
+impl Reconciler {
+    /// `route` installs routes for the subnets behind peers, they are
+    /// recorded in the routes file of `interface`. The state and routes
+    /// files live in `dir`, usually [`crate::key::CONFIG_DIR`].
+    pub fn new(dir: &str, interface: &str, route: bool) -> Self {
+        Reconciler {
+            dir: dir.to_string(),
+            interface: interface.to_string(),
+            route,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// The overlay addresses last asked for with [`Reconciler::set_addresses`].
+    pub fn addresses(&self) -> Vec<IpNet> {
+        self.state.lock().unwrap().addresses.clone()
+    }
+
+    pub fn set_addresses(&self, addrs: &[IpNet]) {
+        let mut state = self.state.lock().unwrap();
+        state.started = true;
+        state.addresses = addrs.to_vec();
+    }
+
+    /// Adds or replaces a peer.
+    pub fn set_peer(&self, peer: Peer) {
+        let mut state = self.state.lock().unwrap();
+        state.started = true;
+        if let Some(listed) = state.listed.as_mut() {
+            listed.push(peer.public_key);
+        }
+        state.peers.retain(|p| p.public_key != peer.public_key);
+        state.peers.push(peer);
+    }
+
+    pub fn remove_peer(&self, public_key: &[u8; 32]) {
+        let mut state = self.state.lock().unwrap();
+        state.started = true;
+        state.peers.retain(|p| &p.public_key != public_key);
+    }
+
+    /// Takes the state saved by an earlier run as the desired one, until the
+    /// controller says otherwise.
+    pub fn restore(&self, snapshot: Snapshot) {
+        let mut state = self.state.lock().unwrap();
+        state.started = true;
+        state.restored = true;
+        state.addresses = snapshot.addresses;
+        state.peers = snapshot.peers;
+    }
+
+    /// Called when a version 1 control stream starts. After a restore the
+    /// controller's first listing is treated as a full resync, so restored
+    /// peers it no longer knows go away. Version 2 wraps the listing in
+    /// resync markers and needs none of this.
+    pub fn begin_initial_sync(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.restored && state.listed.is_none() {
+            state.listed = Some(Vec::new());
+        }
+    }
+
+    /// Called when a version 1 stream goes quiet, the initial listing is
+    /// taken as complete. Not before a peer was listed though, a controller
+    /// slow to start must not take the restored mesh down. Returns whether
+    /// restored peers were dropped.
+    pub fn end_initial_sync(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !state.restored {
+            return false;
+        }
+        let listed = match state.listed.take() {
+            Some(listed) if !listed.is_empty() => listed,
+            listed => {
+                state.listed = listed;
+                return false;
+            }
+        };
+        state.restored = false;
+        let before = state.peers.len();
+        state.peers.retain(|p| listed.contains(&p.public_key));
+        state.peers.len() != before
+    }
+
+    /// Starts a full resync, the controller lists every peer it wants next.
+    pub fn begin_resync(&self) {
+        self.state.lock().unwrap().listed = Some(Vec::new());
+    }
+
+    /// Ends a full resync, dropping the peers that were not listed since
+    /// [`Reconciler::begin_resync`].
+    pub fn end_resync(&self) {
+        let mut state = self.state.lock().unwrap();
+        let Some(listed) = state.listed.take() else {
+            println!("Resync end without a start, ignored");
+            return;
+        };
+        state.restored = false;
+        state.peers.retain(|p| listed.contains(&p.public_key));
+    }
+
+    /// Applies what differs between the desired state and the backend.
+    /// Failures of single peers and routes are logged and retried on the
+    /// next call, only failing to read the backend is an error.
+    pub fn reconcile(&self, adapter: &dyn Backend) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.started {
+            return Ok(());
+        }
+        let stats = adapter.stats()?;
+        let state = &mut *state;
+        let before = applied(state);
+
+        // Peers we configured went missing, somebody reset the interface
+        let reset = state
+            .applied_peers
+            .iter()
+            .any(|p| reported(&stats, &p.public_key).is_none());
+        if reset {
+            println!("Interface was reset, applying the configuration again");
+            state.applied_peers.clear();
+            if !state.addresses.is_empty() {
+                state.applied_addresses.clear();
+            }
+        }
+
+        let installed = state.routes.clone();
+        let addresses_changed = state.applied_addresses != state.addresses;
+        let wanted = if self.route {
+            wanted_routes(&state.peers)
+        } else {
+            Vec::new()
+        };
+
+        // Next hops are only reachable through the interface subnets, take
+        // every route down before those change. A reset may or may not have
+        // taken our routes along, they are deleted and added again so the
+        // records stay right either way.
+        let (stale, kept): (Vec<_>, Vec<_>) = state
+            .routes
+            .drain(..)
+            .partition(|r| reset || addresses_changed || !wanted.contains(r));
+        state.routes = kept;
+        for (dest, peer_addr) in stale {
+            match adapter.del_route(dest, peer_addr) {
+                Ok(()) => println!(" del route for IP: {} via {}", dest, peer_addr),
+                // Gone already, e.g. together with the interface
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(error) => {
+                    println!(
+                        "Failed to delete route for IP: {} with error: {}",
+                        dest, error
+                    );
+                    // Still ours, tried again on the next pass
+                    state.routes.push((dest, peer_addr));
+                }
+            }
+        }
+
+        if addresses_changed && !state.addresses.is_empty() {
+            if !state.applied_addresses.is_empty() {
+                println!(
+                    "Updating interface IP: {} -> {}",
+                    join(&state.applied_addresses),
+                    join(&state.addresses)
+                );
+            }
+            let result = adapter
+                .set_addresses(&state.addresses)
+                .and_then(|()| adapter.up());
+            if let Err(e) = result {
+                // The stale routes are gone already, the file must say so
+                if state.routes != installed {
+                    self.save_routes(&state.routes);
+                }
+                return Err(e);
+            }
+        }
+        state.applied_addresses = state.addresses.clone();
+
+        for peer in &state.peers {
+            let applied = state
+                .applied_peers
+                .iter()
+                .find(|p| p.public_key == peer.public_key);
+            // Endpoints roam, only the allowed IPs are compared with the backend
+            let in_sync = applied == Some(peer)
+                && reported(&stats, &peer.public_key)
+                    .is_some_and(|ips| same_ips(ips, &peer.allowed_ips));
+            if in_sync {
+                continue;
+            }
+            describe(peer);
+            match adapter.set_peer(peer) {
+                Ok(()) => {
+                    state
+                        .applied_peers
+                        .retain(|p| p.public_key != peer.public_key);
+                    state.applied_peers.push(peer.clone());
+                }
+                Err(e) => println!("Failed to set peer: {}", e),
+            }
+        }
+
+        for reported in &stats.peers {
+            if state
+                .peers
+                .iter()
+                .any(|p| p.public_key == reported.public_key)
+            {
+                continue;
+            }
+            match adapter.remove_peer(&reported.public_key) {
+                Ok(()) => {
+                    println!("Removed peer: {}", BASE64.encode(reported.public_key));
+                    state
+                        .applied_peers
+                        .retain(|p| p.public_key != reported.public_key);
+                }
+                Err(e) => println!("Failed to remove peer: {}", e),
+            }
+        }
+        // Peers removed again before they reached the backend
+        let peers = &state.peers;
+        state
+            .applied_peers
+            .retain(|a| peers.iter().any(|p| p.public_key == a.public_key));
+
+        for (dest, peer_addr) in wanted {
+            if state.routes.contains(&(dest, peer_addr)) {
+                continue;
+            }
+            println!(" add route for IP: {} via {}", dest, peer_addr);
+            match adapter.add_route(dest, peer_addr) {
+                Ok(()) => {
+                    println!("Successfully added route for IP: {}", dest);
+                    state.routes.push((dest, peer_addr));
+                }
+                Err(error) => {
+                    println!("Failed to add route for IP: {} with error: {}", dest, error)
+                }
+            }
+        }
+        if state.routes != installed {
+            self.save_routes(&state.routes);
+        }
+        let after = applied(state);
+        if after != before {
+            if let Err(e) = state::save(&self.dir, &self.interface, &after) {
+                println!("Failed to save the state: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes every route this client installed, for shutdown.
+    pub fn teardown(&self, adapter: &dyn Backend) {
+        let mut state = self.state.lock().unwrap();
+        for (dest, peer_addr) in state.routes.drain(..) {
+            match adapter.del_route(dest, peer_addr) {
+                Ok(()) => println!(" del route for IP: {} via {}", dest, peer_addr),
+                Err(error) => println!(
+                    "Failed to delete route for IP: {} with error: {}",
+                    dest, error
+                ),
+            }
+        }
+        self.save_routes(&[]);
+    }
+
+    /// Removes the peers this client configured, for shutdown.
+    pub fn clear_peers(&self, adapter: &dyn Backend) {
+        let mut state = self.state.lock().unwrap();
+        for peer in state.applied_peers.drain(..) {
+            match adapter.remove_peer(&peer.public_key) {
+                Ok(()) => println!("Removed peer: {}", BASE64.encode(peer.public_key)),
+                Err(e) => println!("Failed to remove peer: {}", e),
+            }
+        }
+    }
+
+    fn save_routes(&self, installed: &[(IpNet, IpAddr)]) {
+        if let Err(e) = routes::save(&self.dir, &self.interface, installed) {
+            println!("Failed to record routes: {}", e);
+        }
+    }
+}
+
+fn applied(state: &State) -> Snapshot {
+    Snapshot {
+        addresses: state.applied_addresses.clone(),
+        peers: state.applied_peers.clone(),
+    }
+}
+
+fn describe(peer: &Peer) {
+    let endpoint = peer
+        .endpoint
+        .map(|endpoint| endpoint.to_string())
+        .unwrap_or_else(|| "x".to_string());
+    let ip_str = match peer.allowed_ips.first() {
+        Some(ip) => ip.addr().to_string(),
+        None => "[no allowed ip]".to_string(),
+    };
+    println!(
+        "  add peer: {} {} {}",
+        BASE64.encode(peer.public_key),
+        endpoint,
+        ip_str
+    );
+}
+
+/// The allowed IPs the backend reports for a peer, `None` when it is missing.
+fn reported<'a>(stats: &'a Stats, public_key: &[u8; 32]) -> Option<&'a [IpNet]> {
+    stats
+        .peers
+        .iter()
+        .find(|p| &p.public_key == public_key)
+        .map(|p| p.allowed_ips.as_slice())
+}
+
+fn same_ips(a: &[IpNet], b: &[IpNet]) -> bool {
+    let mut a: Vec<IpNet> = a.iter().map(IpNet::trunc).collect();
+    let mut b: Vec<IpNet> = b.iter().map(IpNet::trunc).collect();
+    a.sort();
+    a.dedup();
+    b.sort();
+    b.dedup();
+    a == b
+}
+
+fn join(addrs: &[IpNet]) -> String {
+    addrs
+        .iter()
+        .map(IpNet::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn wanted_routes(peers: &[Peer]) -> Vec<(IpNet, IpAddr)> {
+    let mut routes = Vec::new();
+    for route in peers.iter().flat_map(|p| peer_routes(&p.allowed_ips)) {
+        if !routes.contains(&route) {
+            routes.push(route);
+        }
+    }
+    routes
+}
+
+/// Pairs the subnets behind a peer with their next hop. The first allowed
+/// IP of each address family is the peer's own address, the later ones of
+/// the same family are routed via it.
+fn peer_routes(allowed_ips: &[IpNet]) -> Vec<(IpNet, IpAddr)> {
+    let mut via_v4 = None;
+    let mut via_v6 = None;
+    let mut routes = Vec::new();
+    for ip in allowed_ips {
+        // Never let a peer take over the default route
+        if ip.addr().is_unspecified() {
+            continue;
+        }
+        let via = match ip {
+            IpNet::V4(_) => &mut via_v4,
+            IpNet::V6(_) => &mut via_v6,
+        };
+        match via {
+            Some(peer_addr) => routes.push((ip.trunc(), *peer_addr)),
+            None => *via = Some(ip.addr()),
+        }
+    }
+    routes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::MockBackend;
+    use std::path::PathBuf;
+
+    /// A state directory of its own for every test, they run in parallel.
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("sitepi-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&dir);
+            Dir(dir)
+        }
+
+        fn reconciler(&self, route: bool) -> Reconciler {
+            Reconciler::new(self.0.to_str().unwrap(), "wgtest", route)
+        }
+
+        fn file(&self, extension: &str) -> Option<String> {
+            std::fs::read_to_string(self.0.join(format!("wgtest.{}", extension))).ok()
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn net(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    fn peer(id: u8, allowed_ips: &[&str]) -> Peer {
+        Peer {
+            public_key: [id; 32],
+            preshared_key: None,
+            endpoint: Some(format!("192.0.2.{}:51820", id).parse().unwrap()),
+            allowed_ips: allowed_ips.iter().map(|ip| net(ip)).collect(),
+            keepalive: 25,
+        }
+    }
+
+    fn keys(adapter: &MockBackend) -> Vec<u8> {
+        let mut keys: Vec<u8> = adapter
+            .state()
+            .peers
+            .iter()
+            .map(|p| p.public_key[0])
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn nothing_before_the_controller_spoke() {
+        let dir = Dir::new("idle");
+        let reconciler = dir.reconciler(true);
+        let adapter = MockBackend::new("wgtest");
+        adapter.set_peer(&peer(9, &["10.9.0.9/32"])).unwrap();
+        reconciler.reconcile(&adapter).unwrap();
+        assert_eq!(keys(&adapter), [9]);
+        assert_eq!(dir.file("state"), None);
+    }
+
+    #[test]
+    fn applies_the_difference() {
+        let dir = Dir::new("diff");
+        let reconciler = dir.reconciler(false);
+        let adapter = MockBackend::new("wgtest");
+        // Left over from an earlier run
+        adapter.set_peer(&peer(9, &["10.9.0.9/32"])).unwrap();
+
+        reconciler.set_addresses(&[net("10.9.0.2/24")]);
+        reconciler.set_peer(peer(1, &["10.9.0.5/32"]));
+        reconciler.set_peer(peer(2, &["10.9.0.6/32"]));
+        reconciler.reconcile(&adapter).unwrap();
+        assert_eq!(keys(&adapter), [1, 2]);
+        assert_eq!(adapter.state().addresses, [net("10.9.0.2/24")]);
+        assert!(adapter.state().up);
+        let saved = state::load(dir.0.to_str().unwrap(), "wgtest").unwrap();
+        assert_eq!(saved.addresses, [net("10.9.0.2/24")]);
+        assert_eq!(saved.peers.len(), 2);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let state_file = dir.0.join("wgtest.state");
+            let mode = std::fs::metadata(state_file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // A changed peer is set again, a removed one goes away
+        reconciler.set_peer(peer(1, &["10.9.0.7/32"]));
+        reconciler.remove_peer(&[2; 32]);
+        reconciler.reconcile(&adapter).unwrap();
+        assert_eq!(keys(&adapter), [1]);
+        assert_eq!(adapter.state().peers[0].allowed_ips, [net("10.9.0.7/32")]);
+        let saved = state::load(dir.0.to_str().unwrap(), "wgtest").unwrap();
+        assert_eq!(saved.peers, [peer(1, &["10.9.0.7/32"])]);
+    }
+
+    #[test]
+    fn repairs_a_reset_interface() {
+        let dir = Dir::new("reset");
+        let reconciler = dir.reconciler(true);
+        let adapter = MockBackend::new("wgtest");
+        reconciler.set_addresses(&[net("10.9.0.2/24")]);
+        reconciler.set_peer(peer(1, &["10.9.0.5/32", "192.168.50.0/24"]));
+        reconciler.reconcile(&adapter).unwrap();
+
+        {
+            let mut state = adapter.state();
+            state.peers.clear();
+            state.addresses.clear();
+            state.routes.clear();
+            state.up = false;
+        }
+        reconciler.reconcile(&adapter).unwrap();
+        assert_eq!(keys(&adapter), [1]);
+        assert_eq!(adapter.state().addresses, [net("10.9.0.2/24")]);
+        assert!(adapter.state().up);
+        assert_eq!(
+            adapter.state().routes,
+            [(net("192.168.50.0/24"), "10.9.0.5".parse().unwrap())]
+        );
+
+        // The routes survived this time, they stay recorded
+        adapter.state().peers.clear();
+        reconciler.reconcile(&adapter).unwrap();
+        assert_eq!(keys(&adapter), [1]);
+        assert_eq!(
+            adapter.state().routes,
+            [(net("192.168.50.0/24"), "10.9.0.5".parse().unwrap())]
+        );
+        assert_eq!(
+            dir.file("routes").as_deref(),
+            Some("192.168.50.0/24 via 10.9.0.5\n")
+        );
+    }
+
+    #[test]
+    fn resync_drops_unlisted_peers() {
+        let dir = Dir::new("resync");
+        let reconciler = dir.reconciler(false);
+        let adapter = MockBackend::new("wgtest");
+        reconciler.set_peer(peer(1, &["10.9.0.5/32"]));
+        reconciler.set_peer(peer(2, &["10.9.0.6/32"]));
+        reconciler.reconcile(&adapter).unwrap();
+
+        reconciler.begin_resync();
+        reconciler.set_peer(peer(2, &["10.9.0.6/32"]));
+        reconciler.set_peer(peer(3, &["10.9.0.7/32"]));
+        // Nothing goes away halfway through the listing
+        reconciler.reconcile(&adapter).unwrap();
+        assert_eq!(keys(&adapter), [1, 2, 3]);
+        reconciler.end_resync();
+        reconciler.reconcile(&adapter).unwrap();
+        assert_eq!(keys(&adapter), [2, 3]);
+
+        // A stray end marker changes nothing
+        reconciler.end_resync();
+        reconciler.reconcile(&adapter).unwrap();
+        assert_eq!(keys(&adapter), [2, 3]);
+    }
+
+    #[test]
+    fn initial_sync_after_a_restore() {
+        let dir = Dir::new("restore");
+        let reconciler = dir.reconciler(false);
+        let adapter = MockBackend::new("wgtest");
+        reconciler.restore(Snapshot {
+            addresses: vec![net("10.9.0.2/24")],
+            peers: vec![peer(1, &["10.9.0.5/32"]), peer(2, &["10.9.0.6/32"])],
+        });
+        reconciler.reconcile(&adapter).unwrap();
+        assert_eq!(keys(&adapter), [1, 2]);
+
+        // A quiet controller does not end the listing
+        reconciler.begin_initial_sync();
+        assert!(!reconciler.end_initial_sync());
+        reconciler.set_peer(peer(2, &["10.9.0.6/32"]));
+        assert!(reconciler.end_initial_sync());
+        reconciler.reconcile(&adapter).unwrap();
+        assert_eq!(keys(&adapter), [2]);
+
+        // Only the first listing counts
+        reconciler.begin_initial_sync();
+        reconciler.set_peer(peer(3, &["10.9.0.7/32"]));
+        assert!(!reconciler.end_initial_sync());
+        reconciler.reconcile(&adapter).unwrap();
+        assert_eq!(keys(&adapter), [2, 3]);
+    }
+
+    #[test]
+    fn routes_follow_the_peers() {
+        let dir = Dir::new("routes");
+        let reconciler = dir.reconciler(true);
+        let adapter = MockBackend::new("wgtest");
+        let via: IpAddr = "10.9.0.5".parse().unwrap();
+        reconciler.set_addresses(&[net("10.9.0.2/24")]);
+        reconciler.set_peer(peer(
+            1,
+            &["10.9.0.5/32", "192.168.50.0/24", "0.0.0.0/0", "fd00::5/128"],
+        ));
+        reconciler.reconcile(&adapter).unwrap();
+        assert_eq!(adapter.state().routes, [(net("192.168.50.0/24"), via)]);
+        assert_eq!(
+            dir.file("routes").as_deref(),
+            Some("192.168.50.0/24 via 10.9.0.5\n")
+        );
+
+        reconciler.set_peer(peer(1, &["10.9.0.5/32", "192.168.60.0/24"]));
+        reconciler.reconcile(&adapter).unwrap();
+        assert_eq!(adapter.state().routes, [(net("192.168.60.0/24"), via)]);
+
+        reconciler.remove_peer(&[1; 32]);
+        reconciler.reconcile(&adapter).unwrap();
+        assert!(adapter.state().routes.is_empty());
+        assert_eq!(dir.file("routes"), None);
+    }
+
+    #[test]
+    fn readdressing_moves_the_routes() {
+        let dir = Dir::new("readdress");
+        let reconciler = dir.reconciler(true);
+        let adapter = MockBackend::new("wgtest");
+        reconciler.set_addresses(&[net("10.9.0.2/24")]);
+        reconciler.set_peer(peer(1, &["10.9.0.5/32", "192.168.50.0/24"]));
+        reconciler.reconcile(&adapter).unwrap();
+
+        reconciler.set_addresses(&[net("10.8.0.2/24")]);
+        reconciler.set_peer(peer(1, &["10.8.0.5/32", "192.168.50.0/24"]));
+        reconciler.reconcile(&adapter).unwrap();
+        assert_eq!(adapter.state().addresses, [net("10.8.0.2/24")]);
+        assert_eq!(
+            adapter.state().routes,
+            [(net("192.168.50.0/24"), "10.8.0.5".parse().unwrap())]
+        );
+        assert_eq!(
+            dir.file("routes").as_deref(),
+            Some("192.168.50.0/24 via 10.8.0.5\n")
+        );
+    }
+
+    #[test]
+    fn teardown_and_sweep() {
+        let dir = Dir::new("teardown");
+        let path = dir.0.to_str().unwrap();
+        let reconciler = dir.reconciler(true);
+        let adapter = MockBackend::new("wgtest");
+        reconciler.set_addresses(&[net("10.9.0.2/24")]);
+        reconciler.set_peer(peer(1, &["10.9.0.5/32", "192.168.50.0/24"]));
+        reconciler.reconcile(&adapter).unwrap();
+
+        // A crashed run leaves the routes file behind
+        let crashed = MockBackend::new("wgtest");
+        crashed.state().routes = adapter.state().routes.clone();
+        routes::sweep(path, "wgtest", &crashed);
+        assert!(crashed.state().routes.is_empty());
+        assert!(routes::load(path, "wgtest").is_empty());
+
+        routes::save(path, "wgtest", &adapter.state().routes).unwrap();
+        reconciler.teardown(&adapter);
+        assert!(adapter.state().routes.is_empty());
+        assert_eq!(dir.file("routes"), None);
+        reconciler.clear_peers(&adapter);
+        assert!(adapter.state().peers.is_empty());
+    }
+
+    #[test]
+    fn failed_readdress_records_the_removed_routes() {
+        let dir = Dir::new("failed");
+        let reconciler = dir.reconciler(true);
+        let adapter = MockBackend::new("wgtest");
+        reconciler.set_addresses(&[net("10.9.0.2/24")]);
+        reconciler.set_peer(peer(1, &["10.9.0.5/32", "192.168.50.0/24"]));
+        reconciler.reconcile(&adapter).unwrap();
+
+        adapter.state().fail_addresses = true;
+        reconciler.set_addresses(&[net("10.8.0.2/24")]);
+        reconciler.set_peer(peer(1, &["10.8.0.5/32", "192.168.50.0/24"]));
+        assert!(reconciler.reconcile(&adapter).is_err());
+        assert!(adapter.state().routes.is_empty());
+        assert_eq!(dir.file("routes"), None);
+
+        adapter.state().fail_addresses = false;
+        reconciler.reconcile(&adapter).unwrap();
+        assert_eq!(adapter.state().addresses, [net("10.8.0.2/24")]);
+        assert_eq!(
+            dir.file("routes").as_deref(),
+            Some("192.168.50.0/24 via 10.8.0.5\n")
+        );
+    }
+
+    #[test]
+    fn no_routes_unless_asked() {
+        let dir = Dir::new("noroute");
+        let reconciler = dir.reconciler(false);
+        let adapter = MockBackend::new("wgtest");
+        reconciler.set_peer(peer(1, &["10.9.0.5/32", "192.168.50.0/24"]));
+        reconciler.reconcile(&adapter).unwrap();
+        assert!(adapter.state().routes.is_empty());
+        assert_eq!(dir.file("routes"), None);
+    }
+}
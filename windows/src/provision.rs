@@ -0,0 +1,127 @@
+//! Provisioning binding, `<CONFIG_DIR>/<interface>.provision` next to the key.
+//!
+//! Once the controller has bound the interface key to a network the
+//! provision code is spent, the binding file records that so restarts do
+//! not need the code again. A different code starts a new enrollment.
+//! The code is only recorded as its SHA-256 in a file readable by the
+//! owner.
+//!
+//! The key the controller signs the control stream with is pinned in
+//! `<CONFIG_DIR>/<interface>.controller` when the controller binds the
+//! interface, from the `x-signing-key` of that answer. While a key is pinned
+//! unsigned lines are refused and other offered keys ignored. The pin only
+//! changes with the next enrollment: to rotate its key the controller hands
+//! out a new provision code, and binding with it pins the key offered then.
+//! Interfaces enrolled before the controller signed stay unpinned until
+//! then. `dir` is [`crate::key::CONFIG_DIR`] outside of tests.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The network an interface key was enrolled into.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Binding {
+    pub network: String,
+    /// Unix time of the enrollment
+    pub bound_at: u64,
+    /// [`digest`] of the provision code that was spent, `None` in bindings
+    /// from before it was recorded
+    pub code: Option<[u8; 32]>,
+}
+
+impl Binding {
+    /// A binding enrolled now.
+    pub fn new(network: &str, code: Option<&str>) -> Self {
+        Binding {
+            network: network.to_string(),
+            bound_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            code: code.map(digest),
+        }
+    }
+
+    /// Whether the binding spent `code`, `None` when it does not say.
+    pub fn spent(&self, code: &str) -> Option<bool> {
+        self.code.map(|spent| spent == digest(code))
+    }
+}
+
+/// How a spent provision code is recorded.
+pub fn digest(code: &str) -> [u8; 32] {
+    Sha256::digest(code.as_bytes()).into()
+}
+
+fn path(dir: &str, interface: &str) -> String {
+    format!("{}/{}.provision", dir, interface)
+}
+
+fn pin_path(dir: &str, interface: &str) -> String {
+    format!("{}/{}.controller", dir, interface)
+}
+
+/// Reads the binding of the interface, `None` when it was never provisioned.
+pub fn load(dir: &str, interface: &str) -> Option<Binding> {
+    let content = std::fs::read_to_string(path(dir, interface)).ok()?;
+    let mut binding = Binding {
+        network: String::new(),
+        bound_at: 0,
+        code: None,
+    };
+    for line in content.lines() {
+        match line.split_once('=') {
+            Some(("network", value)) => binding.network = value.trim().to_string(),
+            Some(("bound_at", value)) => binding.bound_at = value.trim().parse().unwrap_or(0),
+            Some(("code_sha256", value)) => {
+                binding.code = BASE64
+                    .decode(value.trim())
+                    .ok()
+                    .and_then(|digest| digest.try_into().ok())
+            }
+            _ => {}
+        }
+    }
+    Some(binding)
+}
+
+pub fn save(dir: &str, interface: &str, binding: &Binding) -> io::Result<()> {
+    let mut content = format!(
+        "network={}\nbound_at={}\n",
+        binding.network, binding.bound_at
+    );
+    if let Some(code) = &binding.code {
+        content.push_str(&format!("code_sha256={}\n", BASE64.encode(code)));
+    }
+    std::fs::create_dir_all(dir)?;
+    crate::key::write_secret(&path(dir, interface), &content)
+}
+
+/// The pinned controller signing key, `None` before one was pinned.
+pub fn pinned_key(dir: &str, interface: &str) -> Option<[u8; 32]> {
+    let content = std::fs::read_to_string(pin_path(dir, interface)).ok()?;
+    BASE64
+        .decode(content.trim())
+        .ok()
+        .and_then(|key| key.try_into().ok())
+}
+
+/// Pins the controller's ed25519 signing key.
+pub fn pin(dir: &str, interface: &str, controller_key: &[u8; 32]) -> io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(
+        pin_path(dir, interface),
+        format!("{}\n", BASE64.encode(controller_key)),
+    )
+}
+
+/// Forgets the pinned key, for an enrollment without one.
+pub fn unpin(dir: &str, interface: &str) -> io::Result<()> {
+    match std::fs::remove_file(pin_path(dir, interface)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,63 @@
+//! Installed routes, `<CONFIG_DIR>/<interface>.routes` next to the key.
+//!
+//! Every route the client adds is recorded here, one `<dest> via <next hop>`
+//! per line, so routes left behind by a crashed run can be removed on the
+//! next start. `dir` is [`crate::key::CONFIG_DIR`] outside of tests.
+
+use crate::backend::Backend;
+use ipnet::IpNet;
+use std::io;
+use std::net::IpAddr;
+
+fn path(dir: &str, interface: &str) -> String {
+    format!("{}/{}.routes", dir, interface)
+}
+
+/// Reads the recorded routes, skipping lines that do not parse.
+pub fn load(dir: &str, interface: &str) -> Vec<(IpNet, IpAddr)> {
+    let Ok(content) = std::fs::read_to_string(path(dir, interface)) else {
+        return Vec::new();
+    };
+    content
+        .lines()
+        .filter_map(|line| {
+            let (dest, via) = line.split_once(" via ")?;
+            Some((dest.trim().parse().ok()?, via.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// Records `routes` as the installed ones, removing the file when empty.
+pub fn save(dir: &str, interface: &str, routes: &[(IpNet, IpAddr)]) -> io::Result<()> {
+    if routes.is_empty() {
+        return match std::fs::remove_file(path(dir, interface)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+    let content: String = routes
+        .iter()
+        .map(|(dest, via)| format!("{} via {}\n", dest, via))
+        .collect();
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(path(dir, interface), content)
+}
+
+/// Deletes every recorded route from the interface and forgets them.
+pub fn sweep(dir: &str, interface: &str, adapter: &dyn Backend) {
+    let routes = load(dir, interface);
+    for (dest, peer_addr) in &routes {
+        match adapter.del_route(*dest, *peer_addr) {
+            Ok(()) => println!(" del route for IP: {} via {}", dest, peer_addr),
+            // Gone already, e.g. together with the interface
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => println!(
+                "Failed to delete route for IP: {} with error: {}",
+                dest, error
+            ),
+        }
+    }
+    if let Err(e) = save(dir, interface, &[]) {
+        println!("Failed to clear {}: {}", path(dir, interface), e);
+    }
+}
@@ -0,0 +1,75 @@
+//! Last applied network state, `<CONFIG_DIR>/<interface>.state` next to the key.
+//!
+//! Lets the client bring the mesh up again after a reboot while the
+//! controller is unreachable. The file holds an `address <list>` line and
+//! one `wg` line per peer, in the control stream format. `dir` is
+//! [`crate::key::CONFIG_DIR`] outside of tests.
+
+use crate::backend::Peer;
+use crate::key;
+use crate::message::ControlMessage;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ipnet::IpNet;
+use std::io;
+
+/// Overlay addresses and peers as last applied to the interface.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub addresses: Vec<IpNet>,
+    pub peers: Vec<Peer>,
+}
+
+fn path(dir: &str, interface: &str) -> String {
+    format!("{}/{}.state", dir, interface)
+}
+
+/// Reads the saved state, `None` when there is none. Lines that do not
+/// parse are skipped.
+pub fn load(dir: &str, interface: &str) -> Option<Snapshot> {
+    let content = std::fs::read_to_string(path(dir, interface)).ok()?;
+    let mut snapshot = Snapshot::default();
+    for line in content.lines() {
+        if let Some(list) = line.strip_prefix("address ") {
+            snapshot.addresses = list
+                .split(',')
+                .filter_map(|addr| addr.trim().parse().ok())
+                .collect();
+        } else if let Ok(Some(ControlMessage::Peer(peer, _))) = ControlMessage::parse(line) {
+            snapshot.peers.push(peer);
+        }
+    }
+    Some(snapshot)
+}
+
+pub fn save(dir: &str, interface: &str, snapshot: &Snapshot) -> io::Result<()> {
+    let addresses: Vec<String> = snapshot.addresses.iter().map(IpNet::to_string).collect();
+    let mut content = format!("address {}\n", addresses.join(","));
+    for peer in &snapshot.peers {
+        content.push_str(&peer_line(peer));
+    }
+
+    std::fs::create_dir_all(dir)?;
+    // Preshared keys are secrets like the private key
+    key::write_secret(&path(dir, interface), &content)
+}
+
+fn peer_line(peer: &Peer) -> String {
+    let allowed_ips: Vec<String> = peer.allowed_ips.iter().map(IpNet::to_string).collect();
+    format!(
+        "wg {} {} {} {} {}\n",
+        BASE64.encode(peer.public_key),
+        peer.preshared_key
+            .map(|psk| BASE64.encode(psk))
+            .unwrap_or_else(|| "x".to_string()),
+        peer.endpoint
+            .map(|endpoint| endpoint.to_string())
+            .unwrap_or_else(|| "x".to_string()),
+        if allowed_ips.is_empty() {
+            "x".to_string()
+        } else {
+            allowed_ips.join(",")
+        },
+        peer.keepalive
+    )
+}
@@ -0,0 +1,90 @@
+//! Connection status of a running client, `<RUN_DIR>/<interface>.status`.
+//!
+//! Rewritten whenever the connection phase changes or a control message
+//! arrives, so `sitepi --status` can tell a live stream from a stuck one.
+//! The file holds `key=value` lines with unix times and is removed on exit.
+
+use std::fmt;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(windows)]
+const RUN_DIR: &str = crate::key::CONFIG_DIR;
+// tmpfs, the file changes with every heartbeat
+#[cfg(not(windows))]
+const RUN_DIR: &str = "/var/run/sitepi";
+
+/// What the client was last doing.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Status {
+    /// authorizing, streaming, backoff or revoked
+    pub phase: String,
+    /// Unix time the phase was entered
+    pub since: u64,
+    /// Unix time of the last control message, heartbeats included
+    pub last_message: Option<u64>,
+}
+
+fn path(interface: &str) -> String {
+    format!("{}/{}.status", RUN_DIR, interface)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Reads the status of the client on `interface`, `None` when none runs.
+pub fn load(interface: &str) -> Option<Status> {
+    let content = std::fs::read_to_string(path(interface)).ok()?;
+    let mut status = Status::default();
+    for line in content.lines() {
+        match line.split_once('=') {
+            Some(("phase", value)) => status.phase = value.trim().to_string(),
+            Some(("since", value)) => status.since = value.trim().parse().unwrap_or(0),
+            Some(("last_message", value)) => status.last_message = value.trim().parse().ok(),
+            _ => {}
+        }
+    }
+    Some(status)
+}
+
+pub fn save(interface: &str, status: &Status) -> io::Result<()> {
+    let mut content = format!("phase={}\nsince={}\n", status.phase, status.since);
+    if let Some(last_message) = status.last_message {
+        content.push_str(&format!("last_message={}\n", last_message));
+    }
+    std::fs::create_dir_all(RUN_DIR)?;
+    std::fs::write(path(interface), content)
+}
+
+pub fn remove(interface: &str) {
+    let _ = std::fs::remove_file(path(interface));
+}
+
+/// Seconds as `1h2m`, `3m4s` or `5s`.
+fn elapsed(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{}s", secs / 60, secs % 60),
+        _ => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let now = now();
+        writeln!(
+            f,
+            "       Phase: {} for {}",
+            self.phase,
+            elapsed(now.saturating_sub(self.since))
+        )?;
+        match self.last_message {
+            Some(at) => write!(f, "Last message: {} ago", elapsed(now.saturating_sub(at))),
+            None => write!(f, "Last message: never"),
+        }
+    }
+}
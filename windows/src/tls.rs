@@ -0,0 +1,262 @@
+//! TLS settings for the controller connection.
+//!
+//! One connector, with an extra CA bundle and a client certificate when
+//! configured, serves `authorize`, the control stream on every transport
+//! and `offline`, also through the proxy the controller names.
+//!
+//! Pins are base64 SHA-256 digests of a certificate's SubjectPublicKeyInfo,
+//! as `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der |
+//! openssl dgst -sha256 -binary | base64` prints them. With pins set the
+//! controller's certificate must match one, on top of the usual chain
+//! check. It is checked right after the TLS handshake, before any request
+//! is written. reqwest only reports the certificate along with the
+//! response, so with pins set every request goes over a connection of our
+//! own, see [`crate::transport::request`].
+
+use crate::api;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use native_tls::{Certificate, Identity, TlsConnector};
+use reqwest::blocking::ClientBuilder;
+use sha2::{Digest, Sha256};
+use std::io;
+
+#[derive(Clone, Debug)]
+pub struct Tls {
+    connector: TlsConnector,
+    pins: Vec<[u8; 32]>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn read(path: &str) -> io::Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+impl Tls {
+    /// `ca_file` holds PEM certificates trusted besides the system ones,
+    /// `client_cert` a PEM certificate chain and `client_key` its PKCS#8
+    /// PEM key. Pins may carry a `sha256//` prefix.
+    pub fn new(
+        ca_file: Option<&str>,
+        client_cert: Option<&str>,
+        client_key: Option<&str>,
+        pins: &[String],
+    ) -> io::Result<Self> {
+        let mut builder = TlsConnector::builder();
+        if let Some(path) = ca_file {
+            let bundle = String::from_utf8_lossy(&read(path)?).into_owned();
+            let mut count = 0;
+            for block in bundle.split_inclusive("-----END CERTIFICATE-----") {
+                let Some(start) = block.find("-----BEGIN CERTIFICATE-----") else {
+                    continue;
+                };
+                let certificate = Certificate::from_pem(&block.as_bytes()[start..])
+                    .map_err(|e| invalid(format!("{}: {}", path, e)))?;
+                builder.add_root_certificate(certificate);
+                count += 1;
+            }
+            if count == 0 {
+                return Err(invalid(format!("{}: no PEM certificate found", path)));
+            }
+        }
+        match (client_cert, client_key) {
+            (Some(cert), Some(key)) => {
+                let identity = Identity::from_pkcs8(&read(cert)?, &read(key)?)
+                    .map_err(|e| invalid(format!("{}: {}", cert, e)))?;
+                builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => {
+                return Err(invalid(
+                    "a client certificate needs its key and the other way round".to_string(),
+                ))
+            }
+        }
+        let connector = builder.build().map_err(io::Error::other)?;
+
+        let pins = pins
+            .iter()
+            .map(|pin| {
+                let digest = pin.trim().trim_start_matches("sha256//");
+                BASE64
+                    .decode(digest)
+                    .ok()
+                    .and_then(|digest| digest.try_into().ok())
+                    .ok_or_else(|| invalid(format!("pin {} is not a base64 SHA-256", pin)))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Tls { connector, pins })
+    }
+
+    /// A reqwest client using these settings, for when no pins are set.
+    pub(crate) fn client(&self) -> ClientBuilder {
+        reqwest::blocking::Client::builder().use_preconfigured_tls(self.connector.clone())
+    }
+
+    /// Whether the controller's certificate must match a pin.
+    pub(crate) fn pinned(&self) -> bool {
+        !self.pins.is_empty()
+    }
+
+    pub(crate) fn connector(&self) -> &TlsConnector {
+        &self.connector
+    }
+
+    /// Checks the controller's certificate, DER encoded, against the pins.
+    /// Without one, over plain HTTP, nothing matches.
+    pub(crate) fn check(&self, certificate: Option<&[u8]>) -> Result<(), api::Error> {
+        if !self.pinned() {
+            return Ok(());
+        }
+        let digest = certificate
+            .and_then(spki)
+            .map(|spki| <[u8; 32]>::from(Sha256::digest(spki)));
+        match digest {
+            Some(digest) if self.pins.contains(&digest) => Ok(()),
+            _ => Err(api::Error::Unpinned(digest.map(|d| BASE64.encode(d)))),
+        }
+    }
+}
+
+/// Splits the DER element at the start of `der` into the whole element,
+/// its contents and what follows it.
+fn element(der: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let rest = der.get(1..)?;
+    let (&first, mut rest) = rest.split_first()?;
+    let length = if first < 0x80 {
+        first as usize
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let length = rest[..count]
+            .iter()
+            .fold(0usize, |length, byte| length << 8 | *byte as usize);
+        rest = &rest[count..];
+        length
+    };
+    if rest.len() < length {
+        return None;
+    }
+    let header = der.len() - rest.len();
+    Some((&der[..header + length], &rest[..length], &rest[length..]))
+}
+
+/// The SubjectPublicKeyInfo of a DER X.509 certificate, what pins cover.
+fn spki(certificate: &[u8]) -> Option<&[u8]> {
+    let (_, certificate, _) = element(certificate)?;
+    let (_, mut tbs, _) = element(certificate)?;
+    // The version is optional, tagged [0]
+    if tbs.first() == Some(&0xa0) {
+        tbs = element(tbs)?.2;
+    }
+    // Serial number, signature algorithm, issuer, validity and subject
+    for _ in 0..5 {
+        tbs = element(tbs)?.2;
+    }
+    element(tbs).map(|(spki, _, _)| spki)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A DER element with `tag` around `contents`.
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut element = vec![tag];
+        match contents.len() {
+            length @ 0..=0x7f => element.push(length as u8),
+            length @ 0x80..=0xff => element.extend([0x81, length as u8]),
+            length => element.extend([0x82, (length >> 8) as u8, length as u8]),
+        }
+        element.extend_from_slice(contents);
+        element
+    }
+
+    /// A certificate with only the structure `spki` walks, and its
+    /// SubjectPublicKeyInfo.
+    fn certificate(version: bool) -> (Vec<u8>, Vec<u8>) {
+        let algorithm = der(0x30, &der(0x06, &[0x2b, 0x65, 0x70]));
+        let key = der(0x03, &[0x42; 200]);
+        let spki = der(0x30, &[algorithm.clone(), key].concat());
+        let mut tbs = Vec::new();
+        if version {
+            tbs.extend(der(0xa0, &der(0x02, &[2])));
+        }
+        tbs.extend(der(0x02, &[1, 2, 3]));
+        tbs.extend(&algorithm);
+        tbs.extend(der(0x30, b"issuer"));
+        tbs.extend(der(0x30, b"validity"));
+        tbs.extend(der(0x30, b"subject"));
+        tbs.extend(&spki);
+        tbs.extend(der(0xa3, b"extensions"));
+        let signature = der(0x03, &[0x17; 64]);
+        let certificate = der(0x30, &[der(0x30, &tbs), algorithm, signature].concat());
+        (certificate, spki)
+    }
+
+    #[test]
+    fn element_short_and_long_lengths() {
+        assert_eq!(
+            element(&[0x02, 0x01, 0x05, 0xff]),
+            Some((&[0x02, 0x01, 0x05][..], &[0x05][..], &[0xff][..]))
+        );
+        let long = der(0x04, &[7; 300]);
+        let (whole, contents, rest) = element(&long).unwrap();
+        assert_eq!((whole.len(), contents.len(), rest.len()), (304, 300, 0));
+        assert_eq!(
+            element(&[0x30, 0x00]),
+            Some((&[0x30, 0x00][..], &[][..], &[][..]))
+        );
+    }
+
+    #[test]
+    fn element_rejects_malformed() {
+        assert_eq!(element(&[]), None);
+        assert_eq!(element(&[0x30]), None);
+        // Contents shorter than the length
+        assert_eq!(element(&[0x04, 0x03, 1, 2]), None);
+        assert_eq!(element(&[0x04, 0x82, 0x01]), None);
+        // Indefinite and oversized lengths
+        assert_eq!(element(&[0x30, 0x80, 0, 0]), None);
+        assert_eq!(element(&[0x04, 0x85, 0, 0, 0, 0, 1, 0]), None);
+    }
+
+    #[test]
+    fn spki_with_and_without_version() {
+        for version in [true, false] {
+            let (certificate, expected) = certificate(version);
+            assert_eq!(spki(&certificate), Some(&expected[..]));
+        }
+    }
+
+    #[test]
+    fn spki_of_truncated_certificate() {
+        let (certificate, _) = certificate(true);
+        assert_eq!(spki(&certificate[..certificate.len() / 2]), None);
+        assert_eq!(spki(b"not a certificate"), None);
+    }
+
+    #[test]
+    fn check_against_pins() {
+        let (certificate, spki) = certificate(true);
+        let pin = format!("sha256//{}", BASE64.encode(Sha256::digest(&spki)));
+        let tls = Tls::new(None, None, None, &[pin]).unwrap();
+        assert!(tls.check(Some(&certificate)).is_ok());
+        assert!(matches!(tls.check(None), Err(api::Error::Unpinned(None))));
+
+        let tls = Tls::new(None, None, None, &[BASE64.encode([0u8; 32])]).unwrap();
+        assert!(matches!(
+            tls.check(Some(&certificate)),
+            Err(api::Error::Unpinned(Some(_)))
+        ));
+
+        let tls = Tls::new(None, None, None, &[]).unwrap();
+        assert!(tls.check(None).is_ok());
+        assert!(Tls::new(None, None, None, &["short".to_string()]).is_err());
+    }
+}
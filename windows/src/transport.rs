@@ -0,0 +1,589 @@
+//! Control-stream transports.
+//!
+//! The controller serves the stream as a chunked HTTP response with one
+//! message per line, as Server-Sent Events or over a WebSocket. Proxies that
+//! buffer a chunked response usually pass the other two. Every transport
+//! yields the same lines, see [`Stream::read_message`].
+
+use crate::api;
+use crate::tls::Tls;
+use reqwest::Url;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::time::Duration;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{HandshakeError, Message, WebSocket};
+
+/// How the control stream is carried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// A chunked response, one message per line.
+    Chunked,
+    /// `text/event-stream`, one message per `data:` line.
+    Sse,
+    /// One or more messages per text frame.
+    WebSocket,
+}
+
+impl Transport {
+    /// Every transport, in the order the client prefers them.
+    pub const ALL: [Transport; 3] = [Transport::Chunked, Transport::Sse, Transport::WebSocket];
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chunked" => Ok(Transport::Chunked),
+            "sse" => Ok(Transport::Sse),
+            "websocket" => Ok(Transport::WebSocket),
+            _ => Err(format!(
+                "unknown transport '{}', expected chunked, sse or websocket",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Transport::Chunked => "chunked",
+            Transport::Sse => "sse",
+            Transport::WebSocket => "websocket",
+        })
+    }
+}
+
+/// An open control stream.
+pub enum Stream {
+    Chunked {
+        reader: BufReader<Box<dyn Read + Send>>,
+        // Survives read timeouts halfway through a line
+        line: String,
+    },
+    Sse {
+        reader: BufReader<Box<dyn Read + Send>>,
+        line: String,
+        data: String,
+        pending: VecDeque<String>,
+    },
+    WebSocket {
+        socket: Box<WebSocket<MaybeTlsStream<TcpStream>>>,
+        pending: VecDeque<String>,
+    },
+}
+
+impl Stream {
+    /// Reads a response body line by line.
+    pub fn chunked(body: impl Read + Send + 'static) -> Self {
+        Stream::Chunked {
+            reader: BufReader::new(Box::new(body)),
+            line: String::new(),
+        }
+    }
+
+    /// Reads an event stream.
+    pub fn sse(body: impl Read + Send + 'static) -> Self {
+        Stream::Sse {
+            reader: BufReader::new(Box::new(body)),
+            line: String::new(),
+            data: String::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// The next message, `None` when the controller closed the stream. Keep
+    /// alives come back as empty messages. Errors are the read timeout
+    /// expiring as well, see [`crate::api::is_timeout`], the stream stays
+    /// usable after those.
+    pub fn read_message(&mut self) -> io::Result<Option<String>> {
+        match self {
+            Stream::Chunked { reader, line } => {
+                if reader.read_line(line)? == 0 {
+                    return Ok(None);
+                }
+                let message = line.trim_end().to_string();
+                line.clear();
+                Ok(Some(message))
+            }
+            Stream::Sse {
+                reader,
+                line,
+                data,
+                pending,
+            } => loop {
+                if let Some(message) = pending.pop_front() {
+                    return Ok(Some(message));
+                }
+                if reader.read_line(line)? == 0 {
+                    return Ok(None);
+                }
+                let field = line.trim_end_matches(['\r', '\n']);
+                if field.is_empty() {
+                    // End of an event
+                    pending.extend(data.lines().map(String::from));
+                    data.clear();
+                } else if field.starts_with(':') {
+                    // A comment, servers send them to keep the connection up
+                    line.clear();
+                    return Ok(Some(String::new()));
+                } else if let Some(value) = field.strip_prefix("data") {
+                    if let Some(value) = value.strip_prefix(':') {
+                        data.push_str(value.strip_prefix(' ').unwrap_or(value));
+                        data.push('\n');
+                    }
+                }
+                // event, id and retry do not matter here
+                line.clear();
+            },
+            Stream::WebSocket { socket, pending } => loop {
+                if let Some(message) = pending.pop_front() {
+                    return Ok(Some(message));
+                }
+                match socket.read() {
+                    Ok(Message::Text(text)) => pending.extend(text.lines().map(String::from)),
+                    Ok(Message::Binary(_)) => println!("Skipping a binary WebSocket message"),
+                    // Answered by tungstenite, but proof the peer is alive
+                    Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => return Ok(Some(String::new())),
+                    Ok(Message::Close(_)) => return Ok(None),
+                    Ok(Message::Frame(_)) => {}
+                    Err(tungstenite::Error::ConnectionClosed)
+                    | Err(tungstenite::Error::AlreadyClosed) => return Ok(None),
+                    Err(tungstenite::Error::Io(e)) => return Err(e),
+                    Err(e) => return Err(io::Error::other(e)),
+                }
+            },
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Host and port of an URL, with the scheme's default port.
+fn address(url: &Url) -> io::Result<(String, u16)> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| invalid(format!("no host in {}", url)))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| invalid(format!("no port in {}", url)))?;
+    Ok((host.trim_matches(['[', ']']).to_string(), port))
+}
+
+/// The address of an HTTP proxy, WebSockets go through it with CONNECT.
+fn proxy_address(proxy: &str) -> io::Result<(String, u16)> {
+    let proxy = Url::parse(proxy).map_err(|e| invalid(format!("proxy {}: {}", proxy, e)))?;
+    if proxy.scheme() != "http" {
+        return Err(invalid(format!(
+            "WebSocket needs an http:// proxy, not {}",
+            proxy
+        )));
+    }
+    address(&proxy)
+}
+
+/// Asks the proxy on `stream` for a tunnel to `host`:`port`.
+fn tunnel(stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+    let target = if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    write!(
+        stream,
+        "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\nUser-Agent: sitepi\r\n\r\n",
+        target
+    )?;
+
+    // Read the answer byte by byte, nothing after it may be consumed
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte)? == 0 || head.len() > 8192 {
+            return Err(io::Error::other("proxy closed the connection"));
+        }
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(io::Error::other(format!("proxy answered: {}", status)));
+    }
+    Ok(())
+}
+
+/// Connects to the host of `url`, through `proxy` when given, with TLS for
+/// https and wss. The certificate is checked against the pins before
+/// anything is sent. `timeout` applies to every read and write.
+fn open(
+    url: &Url,
+    proxy: Option<&str>,
+    timeout: Duration,
+    tls: &Tls,
+) -> Result<MaybeTlsStream<TcpStream>, api::Error> {
+    let (host, port) = address(url)?;
+    let mut stream = match proxy {
+        Some(proxy) => TcpStream::connect(proxy_address(proxy)?)?,
+        None => TcpStream::connect((host.as_str(), port))?,
+    };
+    // Also bounds the proxy, TLS and HTTP handshakes
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    if proxy.is_some() {
+        tunnel(&mut stream, &host, port)?;
+    }
+    if !matches!(url.scheme(), "https" | "wss") {
+        tls.check(None)?;
+        return Ok(MaybeTlsStream::Plain(stream));
+    }
+
+    let stream = match tls.connector().connect(&host, stream) {
+        Ok(stream) => stream,
+        Err(native_tls::HandshakeError::WouldBlock(_)) => {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out").into())
+        }
+        Err(native_tls::HandshakeError::Failure(e)) => return Err(io::Error::other(e).into()),
+    };
+    let certificate = stream
+        .peer_certificate()
+        .ok()
+        .flatten()
+        .and_then(|certificate| certificate.to_der().ok());
+    tls.check(certificate.as_deref())?;
+    Ok(MaybeTlsStream::NativeTls(stream))
+}
+
+/// Opens the control stream over a WebSocket. `url` may use http(s) as
+/// well, it is switched to ws(s).
+pub fn websocket(
+    url: &str,
+    headers: &[(&'static str, String)],
+    proxy: Option<&str>,
+    read_timeout: Duration,
+    tls: &Tls,
+) -> Result<Stream, api::Error> {
+    let mut url = Url::parse(url).map_err(|e| invalid(format!("{}: {}", url, e)))?;
+    let scheme = match url.scheme() {
+        "http" | "ws" => "ws",
+        "https" | "wss" => "wss",
+        other => return Err(invalid(format!("unsupported scheme {}", other)).into()),
+    };
+    // Only fails between special and non-special schemes
+    let _ = url.set_scheme(scheme);
+
+    let mut request = url
+        .as_str()
+        .into_client_request()
+        .map_err(io::Error::other)?;
+    for (name, value) in headers {
+        let value = HeaderValue::from_str(value).map_err(io::Error::other)?;
+        request.headers_mut().insert(*name, value);
+    }
+
+    // tungstenite's own TLS knows nothing of the CA file, client
+    // certificate or pins
+    let stream = open(&url, proxy, read_timeout, tls)?;
+    match tungstenite::client(request, stream) {
+        Ok((socket, _)) => Ok(Stream::WebSocket {
+            socket: Box::new(socket),
+            pending: VecDeque::new(),
+        }),
+        Err(HandshakeError::Interrupted(_)) => {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "WebSocket handshake timed out").into())
+        }
+        Err(HandshakeError::Failure(tungstenite::Error::Http(response))) => {
+            Err(api::status_error(response.status(), response.headers()))
+        }
+        Err(HandshakeError::Failure(tungstenite::Error::Io(e))) => Err(e.into()),
+        Err(HandshakeError::Failure(e)) => Err(io::Error::other(e).into()),
+    }
+}
+
+/// A request over a connection of our own, for when the pins must be
+/// checked before anything is sent: reqwest only reports the certificate
+/// along with the response. The body runs until the controller closes the
+/// connection, HTTP/1.0 asks for no chunked encoding but some servers use
+/// it anyway.
+pub fn request(
+    method: &str,
+    url: &str,
+    headers: &[(&'static str, String)],
+    proxy: Option<&str>,
+    timeout: Duration,
+    tls: &Tls,
+) -> Result<(StatusCode, HeaderMap, Box<dyn Read + Send>), api::Error> {
+    let url = Url::parse(url).map_err(|e| invalid(format!("{}: {}", url, e)))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(invalid(format!("unsupported scheme {}", url.scheme())).into());
+    }
+    let mut target = url.path().to_string();
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+    let mut host = url.host_str().unwrap_or_default().to_string();
+    if let Some(port) = url.port() {
+        host.push_str(&format!(":{}", port));
+    }
+
+    let mut request = format!("{} {} HTTP/1.0\r\nHost: {}\r\n", method, target, host);
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    // Requests never carry a body
+    if method != "GET" {
+        request.push_str("Content-Length: 0\r\n");
+    }
+    request.push_str("\r\n");
+    let mut stream = BufReader::new(open(&url, proxy, timeout, tls)?);
+    stream.get_mut().write_all(request.as_bytes())?;
+
+    let mut line = String::new();
+    stream.read_line(&mut line)?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| StatusCode::from_bytes(code.as_bytes()).ok())
+        .ok_or_else(|| io::Error::other(format!("bad status line: {}", line.trim_end())))?;
+    let mut headers = HeaderMap::new();
+    loop {
+        line.clear();
+        if stream.read_line(&mut line)? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let Some((name, value)) = line.trim_end().split_once(':') else {
+            break;
+        };
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.trim().as_bytes()),
+            HeaderValue::from_str(value.trim()),
+        ) {
+            headers.append(name, value);
+        }
+    }
+    let chunked = headers
+        .get("transfer-encoding")
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|value| value.to_ascii_lowercase().contains("chunked"));
+    if chunked {
+        return Ok((status, headers, Box::new(Chunked::new(stream))));
+    }
+    Ok((status, headers, Box::new(stream)))
+}
+
+/// Decodes a chunked body. Its state survives read timeouts.
+struct Chunked<R> {
+    inner: R,
+    /// What is left of the current chunk, `None` before a chunk size
+    remaining: Option<usize>,
+    line: String,
+    done: bool,
+}
+
+impl<R: BufRead> Chunked<R> {
+    fn new(inner: R) -> Self {
+        Chunked {
+            inner,
+            remaining: None,
+            line: String::new(),
+            done: false,
+        }
+    }
+
+    /// The next line, kept in `line` across read timeouts.
+    fn read_line(&mut self) -> io::Result<()> {
+        if self.inner.read_line(&mut self.line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !self.done && !buf.is_empty() {
+            match self.remaining {
+                // The line break after the chunk
+                Some(0) => {
+                    self.read_line()?;
+                    self.line.clear();
+                    self.remaining = None;
+                }
+                Some(remaining) => {
+                    let limit = remaining.min(buf.len());
+                    let read = self.inner.read(&mut buf[..limit])?;
+                    if read == 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    self.remaining = Some(remaining - read);
+                    return Ok(read);
+                }
+                None => {
+                    self.read_line()?;
+                    // Chunk extensions follow a ';'
+                    let size = self.line.split(';').next().unwrap_or_default().trim();
+                    let size = usize::from_str_radix(size, 16).map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidData, "bad chunk size")
+                    })?;
+                    self.line.clear();
+                    // Trailers after the last chunk do not matter
+                    self.done = size == 0;
+                    self.remaining = Some(size);
+                }
+            }
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out `parts` one read at a time, a `None` part times out.
+    struct Parts(VecDeque<Option<&'static [u8]>>);
+
+    impl Read for Parts {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                Some(Some(part)) => {
+                    buf[..part.len()].copy_from_slice(part);
+                    Ok(part.len())
+                }
+                Some(None) => Err(io::ErrorKind::WouldBlock.into()),
+                None => Ok(0),
+            }
+        }
+    }
+
+    fn read_all(reader: &mut impl Read) -> (Vec<u8>, usize) {
+        let mut body = Vec::new();
+        let mut timeouts = 0;
+        let mut buf = [0u8; 64];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => return (body, timeouts),
+                Ok(read) => body.extend_from_slice(&buf[..read]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => timeouts += 1,
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn chunked_body() {
+        let body = b"5\r\nhello\r\n7;ext=1\r\n world\n\r\n0\r\nTrailer: x\r\n\r\n";
+        let mut reader = Chunked::new(BufReader::new(&body[..]));
+        assert_eq!(read_all(&mut reader), (b"hello world\n".to_vec(), 0));
+    }
+
+    #[test]
+    fn chunked_survives_timeouts() {
+        let parts = [
+            Some(&b"1"[..]),
+            None,
+            Some(b"0\r\n0123456"),
+            None,
+            Some(b"789abcdef\r"),
+            None,
+            Some(b"\n2\r\nok\r\n0\r\n\r\n"),
+        ];
+        let parts = Parts(parts.into_iter().collect());
+        let mut reader = Chunked::new(BufReader::new(parts));
+        assert_eq!(read_all(&mut reader), (b"0123456789abcdefok".to_vec(), 3));
+    }
+
+    #[test]
+    fn chunked_errors() {
+        let mut reader = Chunked::new(BufReader::new(&b"zz\r\n"[..]));
+        let error = reader.read(&mut [0u8; 8]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let mut reader = Chunked::new(BufReader::new(&b"5\r\nhel"[..]));
+        assert_eq!(reader.read(&mut [0u8; 8]).unwrap(), 3);
+        let error = reader.read(&mut [0u8; 8]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    /// Every message until the end of the stream, timeouts as `None`.
+    fn messages(stream: &mut Stream) -> Vec<Option<String>> {
+        let mut messages = Vec::new();
+        loop {
+            match stream.read_message() {
+                Ok(Some(message)) => messages.push(Some(message)),
+                Ok(None) => return messages,
+                Err(e) if api::is_timeout(&e) => messages.push(None),
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    fn some(message: &str) -> Option<String> {
+        Some(message.to_string())
+    }
+
+    #[test]
+    fn sse_framing() {
+        let body = concat!(
+            ": hello\n\n",
+            "event: msg\nid: 1\ndata: ping\n\n",
+            "data:no space\r\n\r\n",
+            "data: first\ndata:  second\nretry: 10\n\n",
+            "database: not data\ndata\n\n",
+            "data: a\nb\n\n",
+            "data: unterminated\n",
+        );
+        let mut stream = Stream::sse(body.as_bytes());
+        assert_eq!(
+            messages(&mut stream),
+            [
+                some(""),
+                some("ping"),
+                some("no space"),
+                some("first"),
+                some(" second"),
+                some("a"),
+            ]
+        );
+    }
+
+    #[test]
+    fn sse_survives_timeouts() {
+        let parts = [
+            Some(&b"da"[..]),
+            None,
+            Some(b"ta: one\n"),
+            None,
+            Some(b"\ndata: two\n\n"),
+        ];
+        let mut stream = Stream::sse(Parts(parts.into_iter().collect()));
+        assert_eq!(
+            messages(&mut stream),
+            [None, None, some("one"), some("two")]
+        );
+    }
+
+    #[test]
+    fn chunked_lines() {
+        let parts = [
+            Some(&b"ping\r\nwg a"[..]),
+            None,
+            Some(b"b c\n\n"),
+            Some(b"last"),
+        ];
+        let mut stream = Stream::chunked(Parts(parts.into_iter().collect()));
+        assert_eq!(
+            messages(&mut stream),
+            [some("ping"), None, some("wg ab c"), some(""), some("last")]
+        );
+    }
+}